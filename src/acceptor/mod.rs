@@ -1,131 +1,318 @@
-use std::default;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
+use prost::Message;
+
+use crate::checksum;
 use crate::proposal::Proposal;
+use crate::proto::{AcceptorState, SlotPromise};
 
-#[derive(PartialEq, Eq, Default)]
-pub enum Acceptor_Status {
-    #[default]
-    Proposing,
+#[derive(PartialEq, Eq)]
+pub enum AcceptorStatus {
     Accepted,
-    Failed,
-    Reject,
-    Idle,
 }
 
 #[derive(Debug)]
 pub struct Promise {
     promised_proposal_id: i32,
-    accepted_proposal_id: Option<i32>,
-    accepted_value: Option<String>,
+    accepted_proposal: Option<Proposal>,
+}
+
+/// Highest ballot promised in a slot and the value accepted in it, if any.
+#[derive(Debug, Default)]
+struct SlotState {
+    promised: i32,
+    accepted: Option<Proposal>,
 }
 
+/// Paxos acceptor keeping a promise and an accepted value per log slot.
+///
+/// Slots are forgotten once the local learner applied them, see
+/// `forget_through`; the caller refuses those slots from then on.
+///
+/// An acceptor opened on a file writes its state there, sealed with a
+/// CRC32C checksum and synced, before a promise or an acceptance is
+/// returned, so a restarted node keeps its word.
 #[derive(Default)]
 pub struct Acceptor {
-    status: Acceptor_Status,
-    local_generation: usize,
     max_seen_proposal_seq: i32,
-    max_seen_accept_seq: i32,
-    promised_proposals: Vec<Proposal>,
-    accepted_proposals: Vec<Proposal>,
+    last_accepted_slot: i64,
+    slots: BTreeMap<i64, SlotState>,
+    path: Option<PathBuf>,
 }
 
-pub struct Accept_Message {
-    pub status: Acceptor_Status,
+pub struct AcceptMessage {
+    pub status: AcceptorStatus,
     pub proposal_id: i32,
-    pub proposal: Proposal,
 }
 
 impl Acceptor {
     pub fn new() -> Self {
         Self {
-            status: Acceptor_Status::Idle,
-            local_generation: 0,
             max_seen_proposal_seq: 0,
-            max_seen_accept_seq: 0,
-            accepted_proposals: vec![],
-            promised_proposals: vec![],
+            last_accepted_slot: 0,
+            slots: BTreeMap::new(),
+            path: None,
         }
     }
 
-    pub fn prepare(&mut self, proposal: Proposal) -> Option<Promise> {
-        if self.max_seen_proposal_seq > proposal.get_proposal_id().clone() {
-            return None;
-        }
-        self.set_max_seen_proposal_seq(proposal.get_proposal_id().clone());
-        let last_proposal = self.get_last_accepted_proposal();
-        let promised_proposal_id = self.max_seen_proposal_seq as i32;
-        let accepted_proposal_id = match last_proposal {
-            Some(prop) => Some(prop.get_proposal_id() as i32),
-            None => None,
+    /// Acceptor kept in the file at `path`, empty when there is none yet.
+    /// A file that fails verification is refused: promises that cannot be
+    /// read cannot be kept.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut acceptor = Self::new();
+        acceptor.path = Some(path.to_path_buf());
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(acceptor),
+            Err(e) => return Err(e),
         };
-        let accepted_value = match last_proposal {
-            Some(prop) => Some(String::from(prop.get_value())),
-            None => None,
+        let corrupt = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("acceptor state {} {}", path.display(), message),
+            )
         };
-        let promise = Promise::new(promised_proposal_id, accepted_proposal_id, accepted_value);
-        Some(promise)
+        let state = AcceptorState::decode(data.as_slice()).map_err(|e| corrupt(e.to_string()))?;
+        if checksum_of(&state) != state.checksum {
+            return Err(corrupt(String::from("failed checksum verification")));
+        }
+
+        acceptor.max_seen_proposal_seq = state.max_seen_ballot;
+        acceptor.last_accepted_slot = state.last_accepted_slot;
+        for promise in state.slots {
+            let accepted = promise
+                .accepted
+                .as_ref()
+                .map(Proposal::from_proto)
+                .transpose()
+                .map_err(|e| corrupt(e.message().to_string()))?;
+            acceptor.slots.insert(
+                promise.slot,
+                SlotState {
+                    promised: promise.promised,
+                    accepted,
+                },
+            );
+        }
+        Ok(acceptor)
     }
 
-    fn set_max_seen_proposal_seq(&mut self, value: i32) {
-        self.max_seen_proposal_seq = value
+    /// Promises to ignore ballots below the proposal's in its slot and
+    /// returns the value already accepted there. A ballot is promised once
+    /// per slot, so two proposers using the same ballot cannot both win.
+    pub fn prepare(&mut self, proposal: Proposal) -> Option<Promise> {
+        let ballot = proposal.get_proposal_id();
+        let state = self.slots.entry(proposal.get_slot()).or_default();
+        if ballot <= state.promised {
+            return None;
+        }
+        state.promised = ballot;
+        let promise = Promise::new(ballot, state.accepted.clone());
+        self.max_seen_proposal_seq = self.max_seen_proposal_seq.max(ballot);
+        self.persist()?;
+        Some(promise)
     }
 
-    pub fn accept(&mut self, proposal: Proposal) -> Option<Accept_Message> {
-        if self.max_seen_proposal_seq > proposal.get_proposal_id() {
+    pub fn accept(&mut self, proposal: Proposal) -> Option<AcceptMessage> {
+        let ballot = proposal.get_proposal_id();
+        let slot = proposal.get_slot();
+        let state = self.slots.entry(slot).or_default();
+        if ballot < state.promised {
             return None;
         }
+        state.promised = ballot;
+        state.accepted = Some(proposal);
+        self.max_seen_proposal_seq = self.max_seen_proposal_seq.max(ballot);
+        self.last_accepted_slot = self.last_accepted_slot.max(slot);
+        self.persist()?;
+
+        Some(AcceptMessage {
+            status: AcceptorStatus::Accepted,
+            proposal_id: ballot,
+        })
+    }
 
-        let prop = proposal.clone();
+    /// Drops the state of slots up to `slot`, once their values are applied.
+    pub fn forget_through(&mut self, slot: i64) {
+        if self
+            .slots
+            .first_key_value()
+            .is_some_and(|(first, _)| *first <= slot)
+        {
+            self.slots = self.slots.split_off(&(slot + 1));
+            // applied slots are refused either way, the file may lag
+            self.persist();
+        }
+    }
 
-        self.max_seen_accept_seq = prop.get_proposal_id().clone();
-        self.accepted_proposals.push(prop.clone());
+    /// Highest ballot promised in any slot.
+    pub fn get_max_seen_proposal_seq(&self) -> i32 {
+        self.max_seen_proposal_seq
+    }
 
-        Some(Accept_Message {
-            status: Acceptor_Status::Accepted,
-            proposal_id: prop.get_proposal_id().clone(),
-            proposal: prop.clone(),
-        })
+    pub fn get_last_accepted_slot(&self) -> i64 {
+        self.last_accepted_slot
     }
 
-    pub fn get_last_accepted_proposal(&self) -> Option<&Proposal> {
-        if self.accepted_proposals.len() > 0 {
-            let last_accepted_proposal: &Proposal = self
-                .accepted_proposals
-                .last()
-                .expect("cannot get last proposal");
-            Some(last_accepted_proposal)
-        } else {
-            None
+    /// Writes the state to the acceptor's file, `None` when it could not be
+    /// kept and the promise or acceptance must not be sent.
+    fn persist(&self) -> Option<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Some(()),
+        };
+        match self.save(path) {
+            Ok(()) => Some(()),
+            Err(e) => {
+                eprintln!("cannot keep acceptor state in {} {}", path.display(), e);
+                None
+            }
         }
     }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut state = AcceptorState {
+            max_seen_ballot: self.max_seen_proposal_seq,
+            last_accepted_slot: self.last_accepted_slot,
+            slots: self
+                .slots
+                .iter()
+                .map(|(slot, state)| SlotPromise {
+                    slot: *slot,
+                    promised: state.promised,
+                    accepted: state.accepted.as_ref().map(Proposal::to_proto),
+                })
+                .collect(),
+            checksum: 0,
+        };
+        state.checksum = checksum_of(&state);
+
+        let temp_path = path.with_extension("tmp");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(&state.encode_to_vec())?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+fn checksum_of(state: &AcceptorState) -> u32 {
+    let unsealed = AcceptorState {
+        checksum: 0,
+        ..state.clone()
+    };
+    checksum::of(&unsealed.encode_to_vec())
 }
 
 impl Promise {
-    pub fn new(
-        promised_proposal_id: i32,
-        accepted_proposal_id: Option<i32>,
-        accepted_value: Option<String>,
-    ) -> Self {
+    pub fn new(promised_proposal_id: i32, accepted_proposal: Option<Proposal>) -> Self {
         Self {
             promised_proposal_id,
-            accepted_proposal_id,
-            accepted_value,
+            accepted_proposal,
         }
     }
 
     pub fn get_promised_proposal_id(&self) -> i32 {
         self.promised_proposal_id
     }
-    pub fn get_accepted_proposal_id(&self) -> Option<i32> {
-        self.accepted_proposal_id
-    }
-    pub fn get_accepted_value(&self) -> Option<String> {
-        self.accepted_value.clone()
+
+    /// Value accepted in the slot before this promise, with the ballot it
+    /// was accepted in as its proposal id.
+    pub fn get_accepted_proposal(&self) -> Option<&Proposal> {
+        self.accepted_proposal.as_ref()
     }
 }
 
-impl Accept_Message {
-    pub fn get_accepted_proposal(&self) -> Proposal {
-        self.proposal.clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal(ballot: i32, slot: i64, command: &str) -> Proposal {
+        Proposal::new(ballot, command.as_bytes().to_vec()).with_slot(slot)
+    }
+
+    #[test]
+    fn promise_returns_the_value_accepted_in_the_slot() {
+        let mut acceptor = Acceptor::new();
+        acceptor.prepare(proposal(1, 5, "")).unwrap();
+        acceptor.accept(proposal(1, 5, "first")).unwrap();
+        acceptor.accept(proposal(1, 6, "other slot")).unwrap();
+
+        let promise = acceptor.prepare(proposal(2, 5, "")).unwrap();
+        let accepted = promise.get_accepted_proposal().unwrap();
+        assert_eq!(
+            (accepted.get_proposal_id(), accepted.get_command()),
+            (1, &b"first"[..])
+        );
+        assert!(acceptor
+            .prepare(proposal(2, 7, ""))
+            .unwrap()
+            .get_accepted_proposal()
+            .is_none());
+    }
+
+    #[test]
+    fn promises_are_kept_per_slot() {
+        let mut acceptor = Acceptor::new();
+        acceptor.prepare(proposal(5, 1, "")).unwrap();
+
+        assert!(acceptor.prepare(proposal(4, 1, "")).is_none());
+        assert!(acceptor.accept(proposal(4, 1, "stale")).is_none());
+        assert!(acceptor.prepare(proposal(4, 2, "")).is_some());
+        assert_eq!(acceptor.get_max_seen_proposal_seq(), 5);
+    }
+
+    #[test]
+    fn a_ballot_is_promised_once_per_slot() {
+        let mut acceptor = Acceptor::new();
+        assert!(acceptor.prepare(proposal(3, 1, "")).is_some());
+        assert!(acceptor.prepare(proposal(3, 1, "")).is_none());
+        assert!(acceptor.accept(proposal(3, 1, "v")).is_some());
+    }
+
+    #[test]
+    fn promises_survive_reopening() {
+        let path =
+            std::env::temp_dir().join(format!("veresiye-paxos-acceptor-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let mut acceptor = Acceptor::open(&path).unwrap();
+            acceptor.prepare(proposal(3, 1, "")).unwrap();
+            acceptor.accept(proposal(3, 1, "v")).unwrap();
+            acceptor.prepare(proposal(5, 2, "")).unwrap();
+        }
+
+        let mut acceptor = Acceptor::open(&path).unwrap();
+        assert_eq!(acceptor.get_max_seen_proposal_seq(), 5);
+        assert!(acceptor.prepare(proposal(5, 2, "")).is_none());
+        let promise = acceptor.prepare(proposal(4, 1, "")).unwrap();
+        let accepted = promise.get_accepted_proposal().unwrap();
+        assert_eq!(
+            (accepted.get_proposal_id(), accepted.get_command()),
+            (3, &b"v"[..])
+        );
+
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        let opened = Acceptor::open(&path);
+        assert_eq!(opened.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn applied_slots_are_forgotten() {
+        let mut acceptor = Acceptor::new();
+        for slot in 1..=3 {
+            acceptor.accept(proposal(1, slot, "v")).unwrap();
+        }
+        acceptor.forget_through(2);
+        let forgotten = acceptor.prepare(proposal(1, 2, "")).unwrap();
+        assert!(forgotten.get_accepted_proposal().is_none());
+        assert!(acceptor.prepare(proposal(1, 3, "")).is_none());
+        assert_eq!(acceptor.get_last_accepted_slot(), 3);
     }
 }
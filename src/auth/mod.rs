@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use subtle::ConstantTimeEq;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// Header carrying `Bearer <token>`.
+const AUTHORIZATION: &str = "authorization";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Access to every key starting with `prefix`. Write access includes read.
+#[derive(Debug, Clone)]
+pub struct Grant {
+    prefix: String,
+    access: Access,
+}
+
+/// What a role may do: key grants, and cluster administration such as
+/// promoting or removing nodes and taking backups.
+#[derive(Debug, Clone, Default)]
+pub struct Role {
+    grants: Vec<Grant>,
+    admin: bool,
+}
+
+/// Who made a request, attached to it by `Authenticator`.
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// Another node, authenticated with the cluster token.
+    Member,
+    Client {
+        subject: String,
+        roles: Vec<Role>,
+    },
+}
+
+/// Operation a handler asks `Principal::is_allowed` about.
+#[derive(Debug, Clone, Copy)]
+pub enum Permission<'a> {
+    /// A key, or every key under a prefix.
+    Key(Access, &'a str),
+    Admin,
+}
+
+/// Tokens, roles and keys loaded from `AUTH_FILE`:
+///
+/// ```json
+/// {
+///   "cluster_token": "shared by every node",
+///   "jwt_secret": "optional HS256 key",
+///   "roles": {
+///     "orders": {"grants": [{"prefix": "orders/", "access": "write"}]},
+///     "ops": {"admin": true, "grants": [{"prefix": "", "access": "read"}]}
+///   },
+///   "tokens": [{"token": "...", "subject": "billing", "roles": ["orders"]}]
+/// }
+/// ```
+///
+/// JWTs carry the subject in `sub` and role names in `roles`.
+#[derive(Debug)]
+pub struct AuthConfig {
+    cluster_token: String,
+    jwt_secret: Option<String>,
+    roles: HashMap<String, Role>,
+    tokens: Vec<StaticToken>,
+}
+
+#[derive(Debug)]
+struct StaticToken {
+    token: String,
+    subject: String,
+    roles: Vec<String>,
+}
+
+impl AuthConfig {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let config: Value = serde_json::from_slice(&fs::read(path)?)?;
+        Self::parse(&config).map_err(|e| invalid(format!("{} {}", path, e)))
+    }
+
+    pub(crate) fn parse(config: &Value) -> Result<Self, String> {
+        let cluster_token = config["cluster_token"]
+            .as_str()
+            .filter(|token| !token.is_empty())
+            .ok_or("cluster_token is required")?
+            .to_string();
+        let jwt_secret = config["jwt_secret"].as_str().map(String::from);
+
+        let mut roles = HashMap::new();
+        for (name, role) in config["roles"].as_object().into_iter().flatten() {
+            let mut grants = vec![];
+            for grant in role["grants"].as_array().into_iter().flatten() {
+                let prefix = grant["prefix"]
+                    .as_str()
+                    .ok_or(format!("role {} has a grant without prefix", name))?;
+                let access = match grant["access"].as_str() {
+                    Some("read") => Access::Read,
+                    Some("write") => Access::Write,
+                    other => return Err(format!("role {} has access {:?}", name, other)),
+                };
+                grants.push(Grant {
+                    prefix: prefix.to_string(),
+                    access,
+                });
+            }
+            let admin = role["admin"].as_bool().unwrap_or(false);
+            roles.insert(name.clone(), Role { grants, admin });
+        }
+
+        let mut tokens = vec![];
+        for token in config["tokens"].as_array().into_iter().flatten() {
+            let subject = token["subject"].as_str().ok_or("token without subject")?;
+            let value = token["token"]
+                .as_str()
+                .ok_or(format!("token of {} is missing", subject))?;
+            let names = string_list(&token["roles"]);
+            if let Some(name) = names.iter().find(|name| !roles.contains_key(*name)) {
+                return Err(format!("token of {} has unknown role {}", subject, name));
+            }
+            tokens.push(StaticToken {
+                token: value.to_string(),
+                subject: subject.to_string(),
+                roles: names,
+            });
+        }
+
+        Ok(Self {
+            cluster_token,
+            jwt_secret,
+            roles,
+            tokens,
+        })
+    }
+
+    pub fn get_cluster_token(&self) -> &str {
+        &self.cluster_token
+    }
+
+    /// Resolves a bearer token: the cluster token, a static token or a
+    /// JWT signed with `jwt_secret`. Unknown role names grant nothing.
+    ///
+    /// Tokens are compared in constant time and every static token is
+    /// compared, so timing does not reveal how much of a guess matched.
+    pub fn authenticate(&self, token: &str) -> Result<Principal, Status> {
+        if equal(token, &self.cluster_token) {
+            return Ok(Principal::Member);
+        }
+        let matched =
+            self.tokens
+                .iter()
+                .fold(None, |matched, entry| match equal(token, &entry.token) {
+                    true => Some(entry),
+                    false => matched,
+                });
+        let (subject, names) = match matched {
+            Some(entry) => (entry.subject.clone(), entry.roles.clone()),
+            None => self.decode_jwt(token)?,
+        };
+        let roles = names
+            .iter()
+            .filter_map(|name| self.roles.get(name))
+            .cloned()
+            .collect();
+        Ok(Principal::Client { subject, roles })
+    }
+
+    fn decode_jwt(&self, token: &str) -> Result<(String, Vec<String>), Status> {
+        let secret = self
+            .jwt_secret
+            .as_ref()
+            .ok_or_else(|| Status::unauthenticated("unknown token"))?;
+        let claims = jsonwebtoken::decode::<Value>(
+            token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )
+        .map_err(|e| Status::unauthenticated(format!("invalid token {}", e)))?
+        .claims;
+        let subject = claims["sub"]
+            .as_str()
+            .ok_or_else(|| Status::unauthenticated("token has no subject"))?;
+        Ok((subject.to_string(), string_list(&claims["roles"])))
+    }
+}
+
+impl std::fmt::Display for Permission<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permission::Key(Access::Read, key) => write!(f, "read {:?}", key),
+            Permission::Key(Access::Write, key) => write!(f, "write {:?}", key),
+            Permission::Admin => write!(f, "administer the cluster"),
+        }
+    }
+}
+
+impl Principal {
+    pub fn is_member(&self) -> bool {
+        matches!(self, Principal::Member)
+    }
+
+    /// Members may do anything; clients need a role granting `permission`.
+    pub fn is_allowed(&self, permission: Permission) -> bool {
+        let roles = match self {
+            Principal::Member => return true,
+            Principal::Client { roles, .. } => roles,
+        };
+        match permission {
+            Permission::Admin => roles.iter().any(|role| role.admin),
+            Permission::Key(access, key) => {
+                roles.iter().flat_map(|role| &role.grants).any(|grant| {
+                    key.starts_with(&grant.prefix)
+                        && (grant.access == Access::Write || access == Access::Read)
+                })
+            }
+        }
+    }
+
+    pub fn get_subject(&self) -> &str {
+        match self {
+            Principal::Member => "cluster member",
+            Principal::Client { subject, .. } => subject,
+        }
+    }
+}
+
+fn equal(token: &str, expected: &str) -> bool {
+    token.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.as_str().map(String::from))
+        .collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Server interceptor rejecting requests without a valid bearer token and
+/// attaching the `Principal` of the others.
+#[derive(Clone)]
+pub struct Authenticator {
+    config: Option<Arc<AuthConfig>>,
+}
+
+impl Authenticator {
+    /// Lets every request through unauthenticated when `config` is `None`.
+    pub fn new(config: Option<Arc<AuthConfig>>) -> Self {
+        Self { config }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(config) = &self.config else {
+            return Ok(request);
+        };
+        let token = request
+            .metadata()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+        let principal = config.authenticate(token)?;
+        request.extensions_mut().insert(principal);
+        Ok(request)
+    }
+}
+
+/// The principal `Authenticator` attached to `request`.
+pub fn principal<T>(request: &Request<T>) -> Option<&Principal> {
+    request.extensions().get::<Principal>()
+}
+
+/// Client interceptor sending a bearer token, or nothing when unset.
+#[derive(Clone, Default)]
+pub struct BearerToken {
+    value: Option<MetadataValue<Ascii>>,
+}
+
+impl BearerToken {
+    pub fn new(token: Option<&str>) -> Self {
+        Self {
+            value: token.and_then(|token| format!("Bearer {}", token).parse().ok()),
+        }
+    }
+}
+
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(value) = &self.value {
+            request.metadata_mut().insert(AUTHORIZATION, value.clone());
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    fn config() -> AuthConfig {
+        AuthConfig::parse(&json!({
+            "cluster_token": "peers",
+            "jwt_secret": "secret",
+            "roles": {
+                "orders": {"grants": [{"prefix": "orders/", "access": "write"}]},
+                "reader": {"grants": [{"prefix": "", "access": "read"}]},
+                "ops": {"admin": true}
+            },
+            "tokens": [{"token": "t1", "subject": "billing", "roles": ["orders"]}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn static_token_grants_its_prefix_only() {
+        let principal = config().authenticate("t1").unwrap();
+        assert!(principal.is_allowed(Permission::Key(Access::Write, "orders/1")));
+        assert!(principal.is_allowed(Permission::Key(Access::Read, "orders/")));
+        assert!(!principal.is_allowed(Permission::Key(Access::Read, "users/1")));
+        assert!(!principal.is_allowed(Permission::Key(Access::Read, "")));
+        assert!(!principal.is_allowed(Permission::Admin));
+    }
+
+    #[test]
+    fn jwt_roles_are_resolved() {
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({"sub": "dashboard", "roles": ["reader", "ops"], "exp": 4_000_000_000u64}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let principal = config().authenticate(&token).unwrap();
+        assert_eq!(principal.get_subject(), "dashboard");
+        assert!(principal.is_allowed(Permission::Key(Access::Read, "users/1")));
+        assert!(!principal.is_allowed(Permission::Key(Access::Write, "users/1")));
+        assert!(principal.is_allowed(Permission::Admin));
+
+        let forged = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({"sub": "x", "roles": ["ops"], "exp": 4_000_000_000u64}),
+            &EncodingKey::from_secret(b"other"),
+        )
+        .unwrap();
+        assert!(config().authenticate(&forged).is_err());
+    }
+
+    #[test]
+    fn cluster_token_is_a_member() {
+        let config = config();
+        assert!(config.authenticate("peers").unwrap().is_member());
+        assert!(config.authenticate("peer").is_err());
+        assert!(config.authenticate("peersx").is_err());
+        assert!(config.authenticate("nope").is_err());
+    }
+
+    fn call(
+        authenticator: &mut Authenticator,
+        header: Option<&str>,
+    ) -> Result<Request<()>, Status> {
+        let mut request = Request::new(());
+        if let Some(header) = header {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION, header.parse().unwrap());
+        }
+        authenticator.call(request)
+    }
+
+    #[test]
+    fn authenticator_attaches_the_principal() {
+        let mut authenticator = Authenticator::new(Some(Arc::new(config())));
+        let request = call(&mut authenticator, Some("Bearer t1")).unwrap();
+        assert_eq!(principal(&request).unwrap().get_subject(), "billing");
+        let request = call(&mut authenticator, Some("Bearer peers")).unwrap();
+        assert!(principal(&request).unwrap().is_member());
+
+        for header in [None, Some("t1"), Some("Basic t1"), Some("Bearer t2")] {
+            let status = call(&mut authenticator, header).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn authenticator_without_config_lets_everything_through() {
+        let mut authenticator = Authenticator::new(None);
+        let request = call(&mut authenticator, None).unwrap();
+        assert!(principal(&request).is_none());
+    }
+
+    #[test]
+    fn bearer_token_is_what_the_authenticator_reads() {
+        let request = BearerToken::new(Some("t1")).call(Request::new(())).unwrap();
+        let mut authenticator = Authenticator::new(Some(Arc::new(config())));
+        let request = authenticator.call(request).unwrap();
+        assert_eq!(principal(&request).unwrap().get_subject(), "billing");
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::{self, ErrorKind};
+
+/// CRC32C of `data`.
+pub fn of(data: &[u8]) -> u32 {
+    crc32c::crc32c(data)
+}
+
+/// Prefixes `value` with its checksum as 8 hex digits and a `:`.
+pub fn seal(value: &str) -> String {
+    format!("{:08x}:{}", of(value.as_bytes()), value)
+}
+
+/// Strips and verifies the checksum added by `seal`.
+pub fn unseal(sealed: &str) -> io::Result<&str> {
+    let (checksum, value) = match (sealed.get(..8), sealed.get(9..)) {
+        (Some(checksum), Some(value)) if sealed.as_bytes()[8] == b':' => (checksum, value),
+        _ => return Err(corrupt(format!("record {:?} has no checksum", sealed))),
+    };
+    verify(value.as_bytes(), u32::from_str_radix(checksum, 16).ok())?;
+    Ok(value)
+}
+
+/// Fails unless `expected` is the checksum of `data`.
+pub fn verify(data: &[u8], expected: Option<u32>) -> io::Result<()> {
+    let actual = of(data);
+    match expected {
+        Some(expected) if expected == actual => Ok(()),
+        Some(expected) => Err(corrupt(format!(
+            "checksum mismatch, expected {:08x} found {:08x}",
+            expected, actual
+        ))),
+        None => Err(corrupt(String::from("checksum is not a number"))),
+    }
+}
+
+fn corrupt(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_value_round_trips() {
+        for value in ["", "v", "a:b", "ünicode"] {
+            assert_eq!(unseal(&seal(value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn flipped_or_missing_checksum_is_detected() {
+        let sealed = seal("value");
+        let flipped = sealed.replace("value", "valuf");
+        assert!(unseal(&flipped).is_err());
+        assert!(unseal("value").is_err());
+        assert!(unseal("zzzzzzzz:value").is_err());
+    }
+
+    #[test]
+    fn crc32c_known_answer() {
+        assert_eq!(of(b"123456789"), 0xe306_9283);
+    }
+}
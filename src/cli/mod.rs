@@ -0,0 +1,104 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+use crate::auth::BearerToken;
+use crate::export;
+use crate::proto::kv_client_client::KvClientClient;
+use crate::proto::{ExportRequest, ImportChunk};
+
+const USAGE: &str = "usage: veresiye-paxos export <addr> [prefix] | import <addr> <file>";
+
+/// Runs a client subcommand instead of the server when the binary is
+/// started with arguments.
+///
+/// `export <addr> [prefix]` prints the keys of the node at `addr` as JSON
+/// lines, `import <addr> <file>` proposes the lines of `file` in batches
+/// through the node at `addr`.
+///
+/// With `TLS_CA` set the node is dialed over TLS and its certificate must
+/// carry `TLS_SERVER_NAME` (such as `node-1`); `TLS_CERT` and `TLS_KEY`
+/// are presented as the client certificate when set. `AUTH_TOKEN` is sent
+/// as the bearer token.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args {
+        [command, addr, rest @ ..] if command == "export" && rest.len() <= 1 => {
+            let prefix = rest.first().cloned().unwrap_or_default();
+            export_keys(addr, prefix).await
+        }
+        [command, addr, file] if command == "import" => import_keys(addr, file).await,
+        _ => Err(USAGE.into()),
+    }
+}
+
+async fn export_keys(addr: &str, prefix: String) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(addr).await?;
+    let mut stream = client.export(ExportRequest { prefix }).await?.into_inner();
+
+    let mut stdout = io::stdout().lock();
+    let mut count = 0;
+    while let Some(chunk) = stream.message().await? {
+        for line in chunk.lines {
+            writeln!(stdout, "{}", line)?;
+            count += 1;
+        }
+    }
+    stdout.flush()?;
+    eprintln!("exported {} keys from {}", count, addr);
+    Ok(())
+}
+
+async fn import_keys(addr: &str, file: &str) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(file)?;
+    let chunks: Vec<ImportChunk> = contents
+        .lines()
+        .collect::<Vec<&str>>()
+        .chunks(export::LINES_PER_CHUNK)
+        .map(|lines| ImportChunk {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        })
+        .collect();
+
+    let mut client = connect(addr).await?;
+    let reply = client
+        .import(tokio_stream::iter(chunks))
+        .await?
+        .into_inner();
+    eprintln!(
+        "imported {} keys in {} batches into {}",
+        reply.imported, reply.batches, addr
+    );
+    Ok(())
+}
+
+async fn connect(
+    addr: &str,
+) -> Result<KvClientClient<InterceptedService<Channel, BearerToken>>, Box<dyn Error>> {
+    let token = BearerToken::new(env::var("AUTH_TOKEN").ok().as_deref());
+    let ca = match env::var("TLS_CA") {
+        Ok(ca) => ca,
+        Err(_) => {
+            let channel = Endpoint::from_shared(format!("http://{}", addr))?
+                .connect()
+                .await?;
+            return Ok(KvClientClient::with_interceptor(channel, token));
+        }
+    };
+    let server_name =
+        env::var("TLS_SERVER_NAME").map_err(|_| "TLS_SERVER_NAME is required with TLS_CA")?;
+    let mut tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(fs::read(ca)?))
+        .domain_name(server_name);
+    if let (Ok(cert), Ok(key)) = (env::var("TLS_CERT"), env::var("TLS_KEY")) {
+        tls = tls.identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+    }
+    let channel = Endpoint::from_shared(format!("https://{}", addr))?
+        .tls_config(tls)?
+        .connect()
+        .await?;
+    Ok(KvClientClient::with_interceptor(channel, token))
+}
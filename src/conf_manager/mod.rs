@@ -1,8 +1,9 @@
-use std::{env::VarError, future};
+use std::env::VarError;
+use std::fmt::Display;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
 
-use reqwest::{Error, Response};
-use serde_json::json;
-use tower::hedge::Future;
+use crate::paxos::NodeRole;
 
 pub struct ConfigurationManager {
     eureka_address: String,
@@ -11,9 +12,34 @@ pub struct ConfigurationManager {
     host_port: u16,
     node_id: i32,
     app_id: String,
+    health_interval_ms: u64,
+    unreachable_after: u32,
+    static_peers: Vec<(i32, SocketAddr)>,
+    discovery: String,
+    discovery_interval_ms: u64,
+    dns_name: Option<String>,
+    dns_record: String,
+    peers_file: Option<String>,
+    eureka_renewal_secs: u64,
+    node_role: NodeRole,
+    evict_after_secs: Option<u64>,
+    min_cluster_size: usize,
+    data_dir: String,
+    cluster_id: String,
+    restore_from: Option<String>,
+    verify_interval_secs: u64,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_ca: Option<String>,
+    tls_require_client_cert: bool,
+    auth_file: Option<String>,
+    client_port: u16,
+    admin_port: u16,
+    backup_dir: Option<String>,
 }
 
 impl ConfigurationManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         eureka_address: Result<String, VarError>,
         eureka_port: Result<String, VarError>,
@@ -21,31 +47,55 @@ impl ConfigurationManager {
         host_address: Result<String, VarError>,
         host_port: Result<String, VarError>,
         app_id: Result<String, VarError>,
-    ) -> Self {
+        health_interval_ms: Result<String, VarError>,
+        unreachable_after: Result<String, VarError>,
+        peers: Result<String, VarError>,
+        discovery: Result<String, VarError>,
+        discovery_interval_ms: Result<String, VarError>,
+        dns_name: Result<String, VarError>,
+        dns_record: Result<String, VarError>,
+        peers_file: Result<String, VarError>,
+        eureka_renewal_secs: Result<String, VarError>,
+        node_role: Result<String, VarError>,
+        evict_after_secs: Result<String, VarError>,
+        min_cluster_size: Result<String, VarError>,
+        data_dir: Result<String, VarError>,
+        cluster_id: Result<String, VarError>,
+        restore_from: Result<String, VarError>,
+        verify_interval_secs: Result<String, VarError>,
+        tls_cert: Result<String, VarError>,
+        tls_key: Result<String, VarError>,
+        tls_ca: Result<String, VarError>,
+        tls_client_auth: Result<String, VarError>,
+        auth_file: Result<String, VarError>,
+        client_port: Result<String, VarError>,
+        admin_port: Result<String, VarError>,
+        backup_dir: Result<String, VarError>,
+    ) -> Result<Self, String> {
         let eureka_address = match eureka_address {
             Ok(value) => value,
-            Err(e) => {
+            Err(_) => {
                 println!("EUREKA_ADDR not provided using default(host.docker.internal) address");
                 String::from("host.docker.internal")
             }
         };
         let eureka_port: u16 = match eureka_port {
             Ok(value) => value.parse().unwrap(),
-            Err(e) => {
+            Err(_) => {
                 println!("EUREKA_PORT not provided using default(8761) address");
-                8176 as u16
+                8176
             }
         };
         let node_id: i32 = match node_id {
             Ok(value) => value.parse().unwrap(),
-            Err(e) => {
+            Err(_) => {
                 println!("NODE_ID not provided using default(1) id");
-                1 as i32
+                1
             }
         };
         let host_address = match host_address {
             Ok(value) => value.parse().unwrap(),
-            Err(e) => {
+            Err(_) => {
                 println!("HOST_ADDR not provided using default(host.docker.internal) address");
                 String::from("host.docker.internal")
             }
@@ -53,95 +103,570 @@ impl ConfigurationManager {
 
         let host_port: u16 = match host_port {
             Ok(value) => value.parse().unwrap(),
-            Err(e) => {
+            Err(_) => {
                 println!("HOST_PORT not provided using default(9000) id");
-                9000 as u16
+                9000
             }
         };
 
         let app_id = match app_id {
             Ok(value) => value,
-            Err(e) => {
+            Err(_) => {
                 println!("APP_ID not provided using default(veresiye) id");
                 String::from("veresiye")
             }
         };
-        Self {
+
+        let health_interval_ms: u64 = match health_interval_ms {
+            Ok(value) => parse_var("HEALTH_INTERVAL_MS", &value)?,
+            Err(_) => {
+                println!("HEALTH_INTERVAL_MS not provided using default(1000) interval");
+                1000
+            }
+        };
+
+        let unreachable_after: u32 = match unreachable_after {
+            Ok(value) => parse_var("HEALTH_UNREACHABLE_AFTER", &value)?,
+            Err(_) => {
+                println!("HEALTH_UNREACHABLE_AFTER not provided using default(3) failed pings");
+                3
+            }
+        };
+
+        let static_peers = match peers {
+            Ok(value) => match parse_peers(&value) {
+                Ok(peers) => peers,
+                Err(e) => return Err(format!("PEERS is malformed: {e}")),
+            },
+            Err(_) => {
+                println!("PEERS not provided, no static peers configured");
+                vec![]
+            }
+        };
+
+        let discovery = match discovery {
+            Ok(value) => value.to_lowercase(),
+            Err(_) if !static_peers.is_empty() => String::from("static"),
+            Err(_) => {
+                println!("DISCOVERY not provided using default(eureka) backend");
+                String::from("eureka")
+            }
+        };
+
+        let discovery_interval_ms: u64 = match discovery_interval_ms {
+            Ok(value) => parse_var("DISCOVERY_INTERVAL_MS", &value)?,
+            Err(_) => 5000,
+        };
+
+        let dns_name = dns_name.ok();
+
+        let dns_record = match dns_record {
+            Ok(value) => value.to_lowercase(),
+            Err(_) => String::from("srv"),
+        };
+
+        let peers_file = peers_file.ok();
+
+        let eureka_renewal_secs: u64 = match eureka_renewal_secs {
+            Ok(value) => parse_var("EUREKA_RENEWAL_SECS", &value)?,
+            Err(_) => {
+                println!("EUREKA_RENEWAL_SECS not provided using default(30) interval");
+                30
+            }
+        };
+
+        let node_role = match node_role {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "voter" => NodeRole::Voter,
+                "learner" => NodeRole::Learner,
+                _ => return Err(format!("NODE_ROLE `{value}` is not voter or learner")),
+            },
+            Err(_) => {
+                println!("NODE_ROLE not provided using default(voter) role");
+                NodeRole::Voter
+            }
+        };
+
+        let evict_after_secs: Option<u64> = match evict_after_secs {
+            Ok(value) => Some(parse_var("EVICT_AFTER_SECS", &value)?),
+            Err(_) => {
+                println!("EVICT_AFTER_SECS not provided, unreachable nodes are never evicted");
+                None
+            }
+        };
+
+        let min_cluster_size: usize = match min_cluster_size {
+            Ok(value) => parse_var("MIN_CLUSTER_SIZE", &value)?,
+            Err(_) => {
+                println!("MIN_CLUSTER_SIZE not provided using default(3) voters");
+                3
+            }
+        };
+
+        let data_dir = match data_dir {
+            Ok(value) => value,
+            Err(_) => {
+                println!("DATA_DIR not provided using default(./data) directory");
+                String::from("./data")
+            }
+        };
+
+        let cluster_id = match cluster_id {
+            Ok(value) => value,
+            Err(_) => {
+                println!("CLUSTER_ID not provided using APP_ID({}) id", app_id);
+                app_id.clone()
+            }
+        };
+
+        let restore_from = restore_from.ok();
+
+        let verify_interval_secs: u64 = match verify_interval_secs {
+            Ok(value) => parse_var("VERIFY_INTERVAL_SECS", &value)?,
+            Err(_) => {
+                println!("VERIFY_INTERVAL_SECS not provided using default(30) interval");
+                30
+            }
+        };
+
+        let tls_cert = tls_cert.ok();
+        let tls_key = tls_key.ok();
+        let tls_ca = tls_ca.ok();
+        match (&tls_cert, &tls_key, &tls_ca) {
+            (None, None, None) => println!("TLS_CERT not provided, serving plaintext"),
+            (Some(_), Some(_), Some(_)) => {}
+            (Some(_), _, _) => {
+                return Err(String::from(
+                    "TLS_KEY and TLS_CA are required with TLS_CERT",
+                ))
+            }
+            (None, _, _) => return Err(String::from("TLS_KEY and TLS_CA require TLS_CERT")),
+        }
+
+        let tls_require_client_cert = match tls_client_auth {
+            Ok(value) => match value.to_lowercase().as_str() {
+                "required" => true,
+                "optional" => false,
+                _ => {
+                    return Err(format!(
+                        "TLS_CLIENT_AUTH `{value}` is not required or optional"
+                    ))
+                }
+            },
+            Err(_) => true,
+        };
+
+        let auth_file = match auth_file {
+            Ok(value) => Some(value),
+            Err(_) => {
+                println!("AUTH_FILE not provided, requests are not authenticated");
+                None
+            }
+        };
+
+        let client_port: u16 = match client_port {
+            Ok(value) => parse_var("CLIENT_PORT", &value)?,
+            Err(_) => {
+                println!(
+                    "CLIENT_PORT not provided, serving clients on HOST_PORT({})",
+                    host_port
+                );
+                host_port
+            }
+        };
+
+        let admin_port: u16 = match admin_port {
+            Ok(value) => parse_var("ADMIN_PORT", &value)?,
+            Err(_) => {
+                println!(
+                    "ADMIN_PORT not provided, serving admin requests on HOST_PORT({})",
+                    host_port
+                );
+                host_port
+            }
+        };
+
+        let backup_dir = match backup_dir {
+            Ok(value) => Some(value),
+            Err(_) => {
+                println!("BACKUP_DIR not provided, backups are not written on the server");
+                None
+            }
+        };
+
+        Ok(Self {
             eureka_address,
             eureka_port,
             node_id,
             host_address,
             host_port,
             app_id,
-        }
+            health_interval_ms,
+            unreachable_after,
+            static_peers,
+            discovery,
+            discovery_interval_ms,
+            dns_name,
+            dns_record,
+            peers_file,
+            eureka_renewal_secs,
+            node_role,
+            evict_after_secs,
+            min_cluster_size,
+            data_dir,
+            cluster_id,
+            restore_from,
+            verify_interval_secs,
+            tls_cert,
+            tls_key,
+            tls_ca,
+            tls_require_client_cert,
+            auth_file,
+            client_port,
+            admin_port,
+            backup_dir,
+        })
     }
 
-    fn create_eureka_payload(&self, status: &str) -> serde_json::Value {
-        //eureka registration body
-        let payload = json!({
-            "instance": {
-                "instanceId": &self.node_id,
-                "hostName": format!("veresiye-{}", &self.node_id),
-                "app": "veresiye",
-                "ipAddr": &self.host_address,
-                "status": status,
-                "port": {"$": &self.host_port, "@enabled": "true"},
-                "securePort": {"$": 443, "@enabled": "false"},
-                "homePageUrl": format!("http://localhost:{}", &self.host_port),
-                "statusPageUrl": format!("http://localhost:{}", &self.host_port),
-                "healthCheckUrl": format!("http://localhost:{}", &self.host_port),
-                "dataCenterInfo": {
-                    "@class": "com.netflix.appinfo.MyDataCenterInfo",
-                    "name": "MyOwn"
-                },
-                "metadata": {
-                    "leader": "true"
-                }
-            },
-        });
+    pub fn get_eureka_address(&self) -> &str {
+        &self.eureka_address
+    }
+
+    pub fn get_eureka_port(&self) -> &u16 {
+        &self.eureka_port
+    }
+
+    pub fn get_host_address(&self) -> &str {
+        &self.host_address
+    }
+
+    pub fn get_app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    pub fn get_host_port(&self) -> &u16 {
+        &self.host_port
+    }
 
-        payload
+    pub fn get_node_id(&self) -> &i32 {
+        &self.node_id
     }
 
-    fn get_eureka_address(&self) -> String {
-        format!("http://{}:{}", &self.eureka_address, &self.eureka_port)
+    pub fn get_health_interval_ms(&self) -> &u64 {
+        &self.health_interval_ms
     }
 
-    pub fn eureka_registration(&self) -> reqwest::RequestBuilder {
-        let eureka_payload = &self.create_eureka_payload("STARTING");
-        let eureka_address = &self.get_eureka_address();
+    pub fn get_unreachable_after(&self) -> &u32 {
+        &self.unreachable_after
+    }
 
-        let req_client = reqwest::Client::new();
+    pub fn get_static_peers(&self) -> &Vec<(i32, SocketAddr)> {
+        &self.static_peers
+    }
 
-        req_client
-            .post(format!(
-                "{}/eureka/v2/apps/{}",
-                eureka_address, &self.app_id
-            ))
-            .header("Accept", "application/json")
-            .json(&eureka_payload)
+    /// Discovery backend name: `eureka`, `static`, `dns` or `file`.
+    /// Defaults to `static` when `PEERS` is set and `eureka` otherwise.
+    pub fn get_discovery(&self) -> &str {
+        &self.discovery
     }
 
-    pub fn eureka_heartbeat(&self) -> reqwest::RequestBuilder {
-        let eureka_payload = &self.create_eureka_payload("UP");
-        let eureka_address = &self.get_eureka_address();
+    pub fn get_discovery_interval_ms(&self) -> &u64 {
+        &self.discovery_interval_ms
+    }
 
-        let req_client = reqwest::Client::new();
+    pub fn get_dns_name(&self) -> Option<&str> {
+        self.dns_name.as_deref()
+    }
 
-        req_client
-            .post(format!(
-                "{}/eureka/v2/apps/{}/{}",
-                eureka_address, &self.app_id, &self.node_id
-            ))
-            .header("Accept", "application/json")
-            .json(&eureka_payload)
+    /// DNS record type queried by the `dns` backend: `srv` or `a`.
+    pub fn get_dns_record(&self) -> &str {
+        &self.dns_record
     }
 
-    pub fn get_host_port(&self) -> &u16 {
-        &self.host_port
+    pub fn get_peers_file(&self) -> Option<&str> {
+        self.peers_file.as_deref()
     }
 
-    pub fn get_node_id(&self) -> &i32 {
-        &self.node_id
+    pub fn get_eureka_renewal_secs(&self) -> &u64 {
+        &self.eureka_renewal_secs
+    }
+
+    /// Role this node joins with, `NODE_ROLE` is `voter` or `learner`.
+    pub fn get_node_role(&self) -> NodeRole {
+        self.node_role
+    }
+
+    /// Seconds a voter may stay `Unreachable` before the leader removes it.
+    /// Eviction is off when unset.
+    pub fn get_evict_after_secs(&self) -> Option<u64> {
+        self.evict_after_secs
+    }
+
+    /// Eviction never shrinks the cluster below this many voters.
+    pub fn get_min_cluster_size(&self) -> &usize {
+        &self.min_cluster_size
+    }
+
+    pub fn get_data_dir(&self) -> &str {
+        &self.data_dir
+    }
+
+    pub fn get_cluster_id(&self) -> &str {
+        &self.cluster_id
+    }
+
+    /// Backup file a fresh data directory is seeded from.
+    pub fn get_restore_from(&self) -> Option<&str> {
+        self.restore_from.as_deref()
+    }
+
+    /// Seconds between replica consistency checks, 0 turns them off.
+    pub fn get_verify_interval_secs(&self) -> &u64 {
+        &self.verify_interval_secs
+    }
+
+    /// Certificate served to clients and presented to peers, TLS is off
+    /// when unset.
+    pub fn get_tls_cert(&self) -> Option<&str> {
+        self.tls_cert.as_deref()
+    }
+
+    pub fn get_tls_key(&self) -> Option<&str> {
+        self.tls_key.as_deref()
+    }
+
+    /// CA that signs every node and client certificate.
+    pub fn get_tls_ca(&self) -> Option<&str> {
+        self.tls_ca.as_deref()
+    }
+
+    /// Whether clients without a certificate are rejected during the
+    /// handshake, `TLS_CLIENT_AUTH=required`, or may call client RPCs,
+    /// `TLS_CLIENT_AUTH=optional`.
+    pub fn get_tls_require_client_cert(&self) -> bool {
+        self.tls_require_client_cert
+    }
+
+    /// Tokens and roles clients authenticate with, see `auth::AuthConfig`.
+    pub fn get_auth_file(&self) -> Option<&str> {
+        self.auth_file.as_deref()
+    }
+
+    /// Port of the `KvClient` service, `HOST_PORT` when unset. Peers always
+    /// talk to each other on `HOST_PORT`.
+    pub fn get_client_port(&self) -> &u16 {
+        &self.client_port
+    }
+
+    /// Port of the `ClusterAdmin` service, `HOST_PORT` when unset.
+    pub fn get_admin_port(&self) -> &u16 {
+        &self.admin_port
+    }
+
+    /// Directory `backup_to_file` writes into, the RPC is refused when unset.
+    pub fn get_backup_dir(&self) -> Option<&str> {
+        self.backup_dir.as_deref()
+    }
+}
+
+/// Parses the value of the variable `name`, naming it when malformed.
+fn parse_var<T>(name: &str, value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("{name} is malformed: `{value}` {e}"))
+}
+
+/// Parses a `PEERS` list such as `1@10.0.0.1:9000,2@node-2:9000`, resolving
+/// host names once at boot.
+fn parse_peers(value: &str) -> Result<Vec<(i32, SocketAddr)>, String> {
+    let mut peers: Vec<(i32, SocketAddr)> = vec![];
+
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (node_id, addr) = entry
+            .split_once('@')
+            .ok_or_else(|| format!("`{entry}` is not in <node_id>@<host>:<port> form"))?;
+        let node_id: i32 = node_id
+            .parse()
+            .map_err(|e| format!("`{node_id}` is not a valid node id: {e}"))?;
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(|e| format!("cannot resolve `{addr}`: {e}"))?
+            .next()
+            .ok_or_else(|| format!("`{addr}` resolved to no address"))?;
+
+        if peers.iter().any(|(id, _)| *id == node_id) {
+            return Err(format!("node id {node_id} is listed more than once"));
+        }
+        peers.push((node_id, addr));
+    }
+
+    Ok(peers)
+}
+
+#[cfg(test)]
+impl ConfigurationManager {
+    /// Configuration read from `vars` instead of the environment.
+    pub fn from_vars(vars: &[(&str, &str)]) -> Result<Self, String> {
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+                .ok_or(VarError::NotPresent)
+        };
+        Self::new(
+            var("EUREKA_ADDR"),
+            var("EUREKA_PORT"),
+            var("NODE_ID"),
+            var("HOST_ADDR"),
+            var("HOST_PORT"),
+            var("APP_ID"),
+            var("HEALTH_INTERVAL_MS"),
+            var("HEALTH_UNREACHABLE_AFTER"),
+            var("PEERS"),
+            var("DISCOVERY"),
+            var("DISCOVERY_INTERVAL_MS"),
+            var("DNS_NAME"),
+            var("DNS_RECORD"),
+            var("PEERS_FILE"),
+            var("EUREKA_RENEWAL_SECS"),
+            var("NODE_ROLE"),
+            var("EVICT_AFTER_SECS"),
+            var("MIN_CLUSTER_SIZE"),
+            var("DATA_DIR"),
+            var("CLUSTER_ID"),
+            var("RESTORE_FROM"),
+            var("VERIFY_INTERVAL_SECS"),
+            var("TLS_CERT"),
+            var("TLS_KEY"),
+            var("TLS_CA"),
+            var("TLS_CLIENT_AUTH"),
+            var("AUTH_FILE"),
+            var("CLIENT_PORT"),
+            var("ADMIN_PORT"),
+            var("BACKUP_DIR"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_parsed_in_order() {
+        let peers = parse_peers(" 1@127.0.0.1:9001, 2@10.0.0.2:9002 ,3@localhost:9003").unwrap();
+        assert_eq!(
+            peers[..2],
+            [
+                (1, "127.0.0.1:9001".parse().unwrap()),
+                (2, "10.0.0.2:9002".parse().unwrap())
+            ]
+        );
+        assert_eq!(peers[2].0, 3);
+        assert!(peers[2].1.ip().is_loopback());
+    }
+
+    #[test]
+    fn empty_entries_are_skipped() {
+        let peers = parse_peers("1@127.0.0.1:9001,,2@127.0.0.1:9002,").unwrap();
+        assert_eq!(peers.len(), 2);
+        assert!(parse_peers("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_peers_are_rejected() {
+        for (peers, error) in [
+            ("x@127.0.0.1:9001", "node id"),
+            ("1@127.0.0.1", "resolve"),
+            ("1@127.0.0.1:port", "resolve"),
+            ("127.0.0.1:9001", "<node_id>@<host>:<port>"),
+            ("1@127.0.0.1:9001,1@127.0.0.1:9002", "more than once"),
+        ] {
+            let e = parse_peers(peers).unwrap_err();
+            assert!(e.contains(error), "{:?} failed with {}", peers, e);
+        }
+    }
+
+    #[test]
+    fn malformed_peers_fail_the_configuration() {
+        let e = ConfigurationManager::from_vars(&[("PEERS", "1@nowhere")])
+            .err()
+            .unwrap();
+        assert!(e.starts_with("PEERS is malformed"));
+        let conf_manager =
+            ConfigurationManager::from_vars(&[("PEERS", "1@127.0.0.1:9001")]).unwrap();
+        assert_eq!(conf_manager.get_discovery(), "static");
+    }
+
+    #[test]
+    fn malformed_numbers_fail_the_configuration() {
+        for name in [
+            "ADMIN_PORT",
+            "CLIENT_PORT",
+            "VERIFY_INTERVAL_SECS",
+            "MIN_CLUSTER_SIZE",
+            "EVICT_AFTER_SECS",
+            "EUREKA_RENEWAL_SECS",
+            "DISCOVERY_INTERVAL_MS",
+            "HEALTH_INTERVAL_MS",
+            "HEALTH_UNREACHABLE_AFTER",
+        ] {
+            let e = ConfigurationManager::from_vars(&[(name, "soon")])
+                .err()
+                .unwrap();
+            assert!(e.starts_with(&format!("{name} is malformed")), "{}", e);
+        }
+        let conf_manager =
+            ConfigurationManager::from_vars(&[("HEALTH_INTERVAL_MS", "250")]).unwrap();
+        assert_eq!(*conf_manager.get_health_interval_ms(), 250);
+    }
+
+    #[test]
+    fn incomplete_tls_settings_fail_the_configuration() {
+        for vars in [
+            &[("TLS_CERT", "node.pem")][..],
+            &[("TLS_CERT", "node.pem"), ("TLS_KEY", "node.key")][..],
+            &[("TLS_KEY", "node.key")][..],
+            &[("TLS_CA", "ca.pem")][..],
+        ] {
+            assert!(ConfigurationManager::from_vars(vars).is_err(), "{:?}", vars);
+        }
+
+        let tls = [
+            ("TLS_CERT", "node.pem"),
+            ("TLS_KEY", "node.key"),
+            ("TLS_CA", "ca.pem"),
+        ];
+        assert!(ConfigurationManager::from_vars(&tls)
+            .unwrap()
+            .get_tls_require_client_cert());
+        let optional = [tls.as_slice(), &[("TLS_CLIENT_AUTH", "Optional")]].concat();
+        assert!(!ConfigurationManager::from_vars(&optional)
+            .unwrap()
+            .get_tls_require_client_cert());
+        let e = ConfigurationManager::from_vars(&[("TLS_CLIENT_AUTH", "sometimes")])
+            .err()
+            .unwrap();
+        assert!(e.starts_with("TLS_CLIENT_AUTH `sometimes`"), "{}", e);
+    }
+
+    #[test]
+    fn node_role_is_voter_or_learner() {
+        let conf_manager = ConfigurationManager::from_vars(&[("NODE_ROLE", "Learner")]).unwrap();
+        assert_eq!(conf_manager.get_node_role(), NodeRole::Learner);
+        let conf_manager = ConfigurationManager::from_vars(&[]).unwrap();
+        assert_eq!(conf_manager.get_node_role(), NodeRole::Voter);
+        let e = ConfigurationManager::from_vars(&[("NODE_ROLE", "observer")])
+            .err()
+            .unwrap();
+        assert!(e.starts_with("NODE_ROLE `observer`"), "{}", e);
     }
 }
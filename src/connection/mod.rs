@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+
+use crate::auth::BearerToken;
+use crate::proto::paxos_peer_client::PaxosPeerClient;
+use crate::tls::TlsManager;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const BASE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Client for another node, sending the cluster token when auth is on.
+pub type PeerClient = PaxosPeerClient<InterceptedService<Channel, BearerToken>>;
+
+/// Keeps one lazily connected gRPC channel per peer, keyed by node id.
+///
+/// Channels reconnect on their own; the manager only tracks failures so a
+/// peer that keeps failing is backed off instead of retried on every call.
+///
+/// With TLS, every channel presents this node's certificate and only
+/// accepts the server certificate issued to the node it dials.
+pub struct ConnectionManager {
+    peers: Mutex<HashMap<i32, PeerConnection>>,
+    tls: Option<Arc<TlsManager>>,
+    token: BearerToken,
+}
+
+struct PeerConnection {
+    addr: SocketAddr,
+    client: PeerClient,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl ConnectionManager {
+    pub fn new(tls: Option<Arc<TlsManager>>, token: BearerToken) -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+            tls,
+            token,
+        }
+    }
+
+    /// Returns a client for `node_id`, creating the channel on first use or
+    /// when the peer moved to a new address.
+    pub async fn get_client(&self, node_id: i32, addr: SocketAddr) -> Result<PeerClient, Status> {
+        self.client(node_id, addr, true).await
+    }
+
+    /// Same as `get_client` but ignores backoff, for health probes that must
+    /// reach a failing peer to notice it came back.
+    pub async fn get_probe_client(
+        &self,
+        node_id: i32,
+        addr: SocketAddr,
+    ) -> Result<PeerClient, Status> {
+        self.client(node_id, addr, false).await
+    }
+
+    async fn client(
+        &self,
+        node_id: i32,
+        addr: SocketAddr,
+        respect_backoff: bool,
+    ) -> Result<PeerClient, Status> {
+        let mut peers = self.peers.lock().await;
+
+        if let Some(peer) = peers.get(&node_id) {
+            if peer.addr == addr {
+                if let Some(retry_at) = peer.retry_at.filter(|_| respect_backoff) {
+                    if Instant::now() < retry_at {
+                        return Err(Status::unavailable(format!(
+                            "node {} is backing off after {} failures",
+                            node_id, peer.failures
+                        )));
+                    }
+                }
+                return Ok(peer.client.clone());
+            }
+        }
+
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let mut endpoint = Endpoint::from_shared(format!("{}://{}", scheme, addr))
+            .map_err(|e| Status::invalid_argument(format!("invalid peer address {addr}: {e}")))?
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT);
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint
+                .tls_config(tls.get_client_config(node_id))
+                .map_err(|e| {
+                    Status::internal(format!("invalid TLS config for node {node_id}: {e}"))
+                })?;
+        }
+        let client = PaxosPeerClient::with_interceptor(endpoint.connect_lazy(), self.token.clone());
+
+        peers.insert(
+            node_id,
+            PeerConnection {
+                addr,
+                client: client.clone(),
+                failures: 0,
+                retry_at: None,
+            },
+        );
+
+        Ok(client)
+    }
+
+    pub async fn report_success(&self, node_id: i32) {
+        if let Some(peer) = self.peers.lock().await.get_mut(&node_id) {
+            peer.failures = 0;
+            peer.retry_at = None;
+        }
+    }
+
+    /// Records a failed call and pushes the next attempt out exponentially.
+    pub async fn report_failure(&self, node_id: i32) {
+        if let Some(peer) = self.peers.lock().await.get_mut(&node_id) {
+            peer.failures = peer.failures.saturating_add(1);
+            let backoff = BASE_BACKOFF
+                .saturating_mul(1 << peer.failures.min(16))
+                .min(MAX_BACKOFF);
+            peer.retry_at = Some(Instant::now() + backoff);
+        }
+    }
+
+    pub async fn remove(&self, node_id: i32) {
+        self.peers.lock().await.remove(&node_id);
+    }
+
+    /// Drops every channel, the next call to a peer connects again.
+    pub async fn reset(&self) {
+        self.peers.lock().await.clear();
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const LOCK_FILE: &str = "LOCK";
+const MARKER_FILE: &str = "NODE";
+const STORE_DIR: &str = "kv";
+const ACCEPTOR_FILE: &str = "acceptor";
+
+/// Files of a store kept directly in the data directory, as written before
+/// the store moved to `kv/`.
+const LEGACY_STORE_FILES: [&str; 4] = ["manifest", "log", "tables", "keys"];
+
+/// A node's data directory, locked for the lifetime of the process.
+///
+/// The directory holds a `NODE` marker naming the cluster and node it
+/// belongs to, so a node started against another node's directory refuses
+/// to run instead of corrupting it.
+pub struct DataDir {
+    path: PathBuf,
+    // the advisory lock is released when the file is closed
+    _lock: File,
+    marker: String,
+    fresh: bool,
+}
+
+impl DataDir {
+    pub fn open(path: &str, cluster_id: &str, node_id: i32) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        fs::create_dir_all(&path)?;
+
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} is in use by another process", path.display()),
+                ))
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        lock.set_len(0)?;
+        writeln!(&lock, "{}", std::process::id())?;
+
+        let marker = marker(cluster_id, node_id);
+        let fresh = check_marker(&path, &marker)?;
+        println!("using data directory {}", path.display());
+
+        let mut data_dir = Self {
+            path,
+            _lock: lock,
+            marker,
+            fresh,
+        };
+        // a store from before the marker existed belongs to this node,
+        // claim it so it is neither restored over nor left behind
+        if data_dir.fresh && migrate_legacy_store(&data_dir.path)? {
+            data_dir.initialize()?;
+        }
+        Ok(data_dir)
+    }
+
+    /// True until `initialize` ran, i.e. the node never started against
+    /// this directory.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Claims a fresh directory for this node by writing its marker. Called
+    /// once the directory holds the node's initial state, so an interrupted
+    /// restore is retried on the next start.
+    pub fn initialize(&mut self) -> io::Result<()> {
+        if !self.fresh {
+            return Ok(());
+        }
+        let temp_path = self.path.join(format!("{}.tmp", MARKER_FILE));
+        let mut file = File::create(&temp_path)?;
+        file.write_all(self.marker.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(temp_path, self.path.join(MARKER_FILE))?;
+        self.fresh = false;
+        Ok(())
+    }
+
+    /// Directory of the key/value engine.
+    pub fn get_store_path(&self) -> PathBuf {
+        self.path.join(STORE_DIR)
+    }
+
+    /// File the acceptor keeps its promises in.
+    pub fn get_acceptor_path(&self) -> PathBuf {
+        self.path.join(ACCEPTOR_FILE)
+    }
+}
+
+/// Moves a store kept directly in `path` into `kv/`. Returns whether there
+/// was one.
+fn migrate_legacy_store(path: &Path) -> io::Result<bool> {
+    if !path.join("manifest").exists() {
+        return Ok(false);
+    }
+    let store_path = path.join(STORE_DIR);
+    if store_path.exists() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "{} holds both a legacy store and {}, refusing to pick one",
+                path.display(),
+                store_path.display()
+            ),
+        ));
+    }
+
+    // moved into a temporary directory first, so an interrupted migration
+    // is retried instead of leaving a partial store in kv/
+    let temp_path = path.join(format!("{}.migrating", STORE_DIR));
+    fs::create_dir_all(&temp_path)?;
+    for name in LEGACY_STORE_FILES {
+        let legacy = path.join(name);
+        if legacy.exists() {
+            fs::rename(&legacy, temp_path.join(name))?;
+        }
+    }
+    fs::rename(&temp_path, &store_path)?;
+    println!(
+        "moved the store in {} to {}",
+        path.display(),
+        store_path.display()
+    );
+    Ok(true)
+}
+
+fn marker(cluster_id: &str, node_id: i32) -> String {
+    format!("cluster_id={}\nnode_id={}\n", cluster_id, node_id)
+}
+
+/// Requires an existing marker to match. Returns whether there is none yet.
+fn check_marker(path: &Path, expected: &str) -> io::Result<bool> {
+    let marker_path = path.join(MARKER_FILE);
+    if !marker_path.exists() {
+        return Ok(true);
+    }
+
+    let found = fs::read_to_string(&marker_path)?;
+    if found != expected {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} belongs to {}, refusing to start as {}",
+                path.display(),
+                found.trim().replace('\n', " "),
+                expected.trim().replace('\n', " ")
+            ),
+        ));
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "veresiye-paxos-data-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn directory_is_locked_to_one_process() {
+        let path = data_path("lock");
+        let first = DataDir::open(path.to_str().unwrap(), "c", 1).unwrap();
+        let second = DataDir::open(path.to_str().unwrap(), "c", 1);
+        assert_eq!(second.err().unwrap().kind(), ErrorKind::AlreadyExists);
+
+        drop(first);
+        assert!(DataDir::open(path.to_str().unwrap(), "c", 1).is_ok());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn other_node_or_cluster_is_refused() {
+        let path = data_path("marker");
+        let mut data_dir = DataDir::open(path.to_str().unwrap(), "c", 1).unwrap();
+        assert!(data_dir.is_fresh());
+        data_dir.initialize().unwrap();
+        drop(data_dir);
+
+        for (cluster_id, node_id) in [("c", 2), ("d", 1)] {
+            let opened = DataDir::open(path.to_str().unwrap(), cluster_id, node_id);
+            assert_eq!(opened.err().unwrap().kind(), ErrorKind::InvalidData);
+        }
+        let reopened = DataDir::open(path.to_str().unwrap(), "c", 1).unwrap();
+        assert!(!reopened.is_fresh());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn legacy_store_is_moved_and_claimed() {
+        let path = data_path("legacy");
+        fs::create_dir_all(path.join("log")).unwrap();
+        fs::create_dir_all(path.join("tables")).unwrap();
+        fs::write(path.join("manifest"), "m").unwrap();
+        fs::write(path.join("keys"), "k").unwrap();
+
+        let data_dir = DataDir::open(path.to_str().unwrap(), "c", 1).unwrap();
+        assert!(!data_dir.is_fresh());
+        let store_path = data_dir.get_store_path();
+        for name in LEGACY_STORE_FILES {
+            assert!(store_path.join(name).exists(), "{} was not moved", name);
+            assert!(!path.join(name).exists(), "{} was left behind", name);
+        }
+        drop(data_dir);
+        assert!(DataDir::open(path.to_str().unwrap(), "c", 2).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
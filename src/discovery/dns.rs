@@ -0,0 +1,141 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hickory_resolver::TokioAsyncResolver;
+
+use super::{Discovery, DiscoveryError, Peer};
+use crate::auth::BearerToken;
+use crate::proto::paxos_peer_client::PaxosPeerClient;
+use crate::proto::PingRequest;
+
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub enum DnsRecord {
+    /// SRV records; the node id is the ordinal suffix of the target's first
+    /// label, as in `veresiye-2.veresiye.default.svc.cluster.local`.
+    Srv,
+    /// A/AAAA records on the given port; each address is asked for its node
+    /// id through the `ping` RPC.
+    A(u16),
+}
+
+/// Finds peers through DNS, as provided by Kubernetes headless services or
+/// Nomad/Consul service records. Registration is left to the platform.
+pub struct DnsDiscovery {
+    name: String,
+    record: DnsRecord,
+    token: BearerToken,
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsDiscovery {
+    /// `token` authenticates the pings identifying A records. The resolver
+    /// is configured from the system once, and caches across polls.
+    pub fn new(
+        name: String,
+        record: DnsRecord,
+        token: BearerToken,
+    ) -> Result<Self, DiscoveryError> {
+        Ok(Self {
+            name,
+            record,
+            token,
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+        })
+    }
+
+    async fn list_srv(&self) -> Result<Vec<Peer>, DiscoveryError> {
+        let mut peers = vec![];
+        for srv in self.resolver.srv_lookup(self.name.as_str()).await?.iter() {
+            let target = srv.target().to_utf8();
+            let node_id = match node_id_from_host(&target) {
+                Some(node_id) => node_id,
+                None => {
+                    eprintln!("cannot derive node id from {}, skipped", target);
+                    continue;
+                }
+            };
+            if let Some(ip) = self
+                .resolver
+                .lookup_ip(target.as_str())
+                .await?
+                .iter()
+                .next()
+            {
+                peers.push(Peer {
+                    node_id,
+                    addr: SocketAddr::new(ip, srv.port()),
+                });
+            }
+        }
+        Ok(peers)
+    }
+
+    async fn list_a(&self, port: u16) -> Result<Vec<Peer>, DiscoveryError> {
+        let mut peers = vec![];
+        for ip in self.resolver.lookup_ip(self.name.as_str()).await?.iter() {
+            let addr = SocketAddr::new(ip, port);
+            match identify(addr, self.token.clone()).await {
+                Ok(node_id) => peers.push(Peer { node_id, addr }),
+                Err(e) => eprintln!("cannot identify node at {}, skipped {}", addr, e),
+            }
+        }
+        Ok(peers)
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for DnsDiscovery {
+    async fn register(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn deregister(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn heartbeat(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn list_peers(&self) -> Result<Vec<Peer>, DiscoveryError> {
+        match self.record {
+            DnsRecord::Srv => self.list_srv().await,
+            DnsRecord::A(port) => self.list_a(port).await,
+        }
+    }
+}
+
+fn node_id_from_host(host: &str) -> Option<i32> {
+    let label = host.split('.').next()?;
+    let (_, ordinal) = label.rsplit_once('-')?;
+    ordinal.parse().ok()
+}
+
+async fn identify(addr: SocketAddr, token: BearerToken) -> Result<i32, DiscoveryError> {
+    let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", addr))?
+        .connect_timeout(IDENTIFY_TIMEOUT)
+        .timeout(IDENTIFY_TIMEOUT)
+        .connect()
+        .await?;
+    let ping = PaxosPeerClient::with_interceptor(channel, token)
+        .ping(PingRequest {})
+        .await?;
+    Ok(ping.into_inner().node_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_id_is_the_ordinal_of_the_first_label() {
+        assert_eq!(
+            node_id_from_host("veresiye-2.veresiye.default.svc.cluster.local."),
+            Some(2)
+        );
+        assert_eq!(node_id_from_host("my-db-10"), Some(10));
+        assert_eq!(node_id_from_host("veresiye.default.svc"), None);
+        assert_eq!(node_id_from_host("veresiye-x.default.svc"), None);
+    }
+}
@@ -0,0 +1,306 @@
+use reqwest::StatusCode;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use super::{Discovery, DiscoveryError, InstanceStatus, Peer};
+use crate::conf_manager::ConfigurationManager;
+
+/// Registers the node with a Eureka server over its REST API.
+pub struct EurekaDiscovery {
+    eureka_address: String,
+    eureka_port: u16,
+    host_address: String,
+    host_port: u16,
+    node_id: i32,
+    app_id: String,
+    client: reqwest::Client,
+    status: Mutex<InstanceStatus>,
+    leader: Mutex<bool>,
+}
+
+impl EurekaDiscovery {
+    pub fn new(conf_manager: &ConfigurationManager) -> Self {
+        Self {
+            eureka_address: String::from(conf_manager.get_eureka_address()),
+            eureka_port: *conf_manager.get_eureka_port(),
+            host_address: String::from(conf_manager.get_host_address()),
+            host_port: *conf_manager.get_host_port(),
+            node_id: *conf_manager.get_node_id(),
+            app_id: String::from(conf_manager.get_app_id()),
+            client: reqwest::Client::new(),
+            status: Mutex::new(InstanceStatus::Starting),
+            leader: Mutex::new(false),
+        }
+    }
+
+    fn create_eureka_payload(&self, status: &str, leader: bool) -> serde_json::Value {
+        //eureka registration body
+        let payload = json!({
+            "instance": {
+                "instanceId": &self.node_id,
+                "hostName": format!("veresiye-{}", &self.node_id),
+                "app": "veresiye",
+                "ipAddr": &self.host_address,
+                "status": status,
+                "port": {"$": &self.host_port, "@enabled": "true"},
+                "securePort": {"$": 443, "@enabled": "false"},
+                "homePageUrl": format!("http://localhost:{}", &self.host_port),
+                "statusPageUrl": format!("http://localhost:{}", &self.host_port),
+                "healthCheckUrl": format!("http://localhost:{}", &self.host_port),
+                "dataCenterInfo": {
+                    "@class": "com.netflix.appinfo.MyDataCenterInfo",
+                    "name": "MyOwn"
+                },
+                "metadata": {
+                    "leader": leader.to_string(),
+                    "nodeId": self.node_id.to_string()
+                }
+            },
+        });
+
+        payload
+    }
+
+    fn get_eureka_address(&self) -> String {
+        format!("http://{}:{}", &self.eureka_address, &self.eureka_port)
+    }
+
+    fn get_instance_url(&self) -> String {
+        format!(
+            "{}/eureka/v2/apps/{}/{}",
+            self.get_eureka_address(),
+            &self.app_id,
+            &self.node_id
+        )
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for EurekaDiscovery {
+    /// Registers with the last announced status, so a lease renewed after
+    /// eviction does not flip an `UP` node back to `STARTING`.
+    async fn register(&self) -> Result<(), DiscoveryError> {
+        let status = *self.status.lock().await;
+        let leader = *self.leader.lock().await;
+        let eureka_payload = &self.create_eureka_payload(status.as_str(), leader);
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/eureka/v2/apps/{}",
+                self.get_eureka_address(),
+                &self.app_id
+            ))
+            .header("Accept", "application/json")
+            .json(&eureka_payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        println!("instance registered successfuly {:?}", response);
+
+        Ok(())
+    }
+
+    async fn deregister(&self) -> Result<(), DiscoveryError> {
+        self.client
+            .delete(self.get_instance_url())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Renews the lease, registering again if eureka already evicted us.
+    async fn heartbeat(&self) -> Result<(), DiscoveryError> {
+        let response = self.client.put(self.get_instance_url()).send().await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            println!("lease expired on eureka, registering again");
+            return self.register().await;
+        }
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn set_status(&self, status: InstanceStatus) -> Result<(), DiscoveryError> {
+        self.client
+            .put(format!("{}/status", self.get_instance_url()))
+            .query(&[("value", status.as_str())])
+            .send()
+            .await?
+            .error_for_status()?;
+        *self.status.lock().await = status;
+
+        Ok(())
+    }
+
+    async fn set_leader(&self, leader: bool) -> Result<(), DiscoveryError> {
+        *self.leader.lock().await = leader;
+        self.client
+            .put(format!("{}/metadata", self.get_instance_url()))
+            .query(&[("leader", leader.to_string())])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Reads every instance of `app_id` back from the registry. Instances
+    /// that are `DOWN` or missing an address are skipped.
+    async fn list_peers(&self) -> Result<Vec<Peer>, DiscoveryError> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/eureka/v2/apps/{}",
+                self.get_eureka_address(),
+                &self.app_id
+            ))
+            .header("Accept", "application/json")
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        let registry: serde_json::Value = response.error_for_status()?.json().await?;
+
+        let mut peers = vec![];
+        for (node_id, host, port) in parse_registry(&registry) {
+            match tokio::net::lookup_host(format!("{}:{}", host, port)).await {
+                Ok(mut addrs) => {
+                    if let Some(addr) = addrs.next() {
+                        peers.push(Peer { node_id, addr });
+                    }
+                }
+                Err(e) => eprintln!("cannot resolve {} for node {} {}", host, node_id, e),
+            }
+        }
+
+        Ok(peers)
+    }
+}
+
+/// Node id, host and port of every instance of an application in the
+/// registry that is not `DOWN` or `UNKNOWN`.
+fn parse_registry(registry: &serde_json::Value) -> Vec<(i32, String, u16)> {
+    // eureka renders a single instance as an object instead of a list
+    let instances = match &registry["application"]["instance"] {
+        serde_json::Value::Array(instances) => instances.iter().collect(),
+        serde_json::Value::Null => vec![],
+        instance => vec![instance],
+    };
+
+    let mut parsed = vec![];
+    for instance in instances {
+        if matches!(instance["status"].as_str(), Some("DOWN") | Some("UNKNOWN")) {
+            continue;
+        }
+        match parse_instance(instance) {
+            Some(instance) => parsed.push(instance),
+            None => eprintln!("cannot parse eureka instance {}", instance["instanceId"]),
+        }
+    }
+    parsed
+}
+
+/// Extracts node id, host and port from a registry instance. The node id
+/// comes from the `nodeId` metadata we register with, falling back to
+/// `instanceId`.
+fn parse_instance(instance: &serde_json::Value) -> Option<(i32, String, u16)> {
+    let node_id = as_i64(&instance["metadata"]["nodeId"]).or(as_i64(&instance["instanceId"]))?;
+    let host = instance["ipAddr"].as_str()?;
+    let port = as_i64(&instance["port"]["$"])?;
+
+    Some((
+        i32::try_from(node_id).ok()?,
+        String::from(host),
+        u16::try_from(port).ok()?,
+    ))
+}
+
+/// Eureka serialises numbers as JSON numbers or strings depending on the
+/// server version.
+fn as_i64(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_i64(),
+        serde_json::Value::String(text) => text.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn instance(node_id: i32, status: &str) -> serde_json::Value {
+        json!({
+            "instanceId": format!("host-{}", node_id),
+            "ipAddr": format!("10.0.0.{}", node_id),
+            "status": status,
+            "port": {"$": 9000, "@enabled": "true"},
+            "metadata": {"nodeId": node_id.to_string()}
+        })
+    }
+
+    fn registry(instances: serde_json::Value) -> serde_json::Value {
+        json!({"application": {"name": "VERESIYE", "instance": instances}})
+    }
+
+    #[test]
+    fn single_instance_is_an_object() {
+        let parsed = parse_registry(&registry(instance(1, "UP")));
+        assert_eq!(parsed, vec![(1, String::from("10.0.0.1"), 9000)]);
+    }
+
+    #[test]
+    fn down_and_unknown_instances_are_skipped() {
+        let instances = json!([
+            instance(1, "UP"),
+            instance(2, "DOWN"),
+            instance(3, "UNKNOWN"),
+            instance(4, "OUT_OF_SERVICE"),
+            instance(5, "STARTING")
+        ]);
+        let node_ids: Vec<i32> = parse_registry(&registry(instances))
+            .into_iter()
+            .map(|(node_id, _, _)| node_id)
+            .collect();
+        assert_eq!(node_ids, vec![1, 4, 5]);
+        assert!(parse_registry(&json!({"application": {}})).is_empty());
+    }
+
+    #[test]
+    fn node_id_falls_back_to_the_instance_id() {
+        let mut numeric = instance(1, "UP");
+        numeric["metadata"] = json!({});
+        numeric["instanceId"] = json!("7");
+        assert_eq!(parse_instance(&numeric).unwrap().0, 7);
+
+        // an instance id that is a host name identifies no node
+        let mut named = instance(1, "UP");
+        named["metadata"] = json!({"leader": "false"});
+        assert_eq!(parse_instance(&named), None);
+    }
+
+    #[test]
+    fn numbers_may_be_strings() {
+        let mut quoted = instance(2, "UP");
+        quoted["port"] = json!({"$": "9002"});
+        quoted["metadata"]["nodeId"] = json!(2);
+        assert_eq!(
+            parse_instance(&quoted),
+            Some((2, String::from("10.0.0.2"), 9002))
+        );
+
+        let mut invalid = instance(2, "UP");
+        invalid["port"] = json!({"$": 70000});
+        assert_eq!(parse_instance(&invalid), None);
+        invalid["port"] = json!({"$": "ninety"});
+        assert_eq!(parse_instance(&invalid), None);
+    }
+}
@@ -0,0 +1,107 @@
+use std::net::SocketAddr;
+
+use super::{Discovery, DiscoveryError, Peer};
+
+/// Reads peers from a JSON file that is re-read on every poll, e.g.
+/// `[{"node_id": 1, "addr": "10.0.0.1:9000"}]` rendered by a config agent.
+pub struct FileDiscovery {
+    path: String,
+}
+
+impl FileDiscovery {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for FileDiscovery {
+    async fn register(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn deregister(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn heartbeat(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn list_peers(&self) -> Result<Vec<Peer>, DiscoveryError> {
+        let content = tokio::fs::read_to_string(&self.path).await?;
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&content)?;
+
+        let mut peers = vec![];
+        for entry in entries {
+            let node_id = entry["node_id"]
+                .as_i64()
+                .ok_or_else(|| format!("peer entry {entry} has no node_id"))?;
+            let node_id = i32::try_from(node_id)
+                .map_err(|_| format!("peer entry {entry} has an out of range node_id"))?;
+            let addr = entry["addr"]
+                .as_str()
+                .ok_or_else(|| format!("peer entry {entry} has no addr"))?;
+            let addr: SocketAddr = tokio::net::lookup_host(addr)
+                .await?
+                .next()
+                .ok_or_else(|| format!("{addr} resolved to no address"))?;
+            peers.push(Peer { node_id, addr });
+        }
+
+        Ok(peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    async fn list(content: &str) -> Result<Vec<Peer>, DiscoveryError> {
+        // every call gets its own file, tests run in parallel
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "veresiye-paxos-peers-{}-{}.json",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::SeqCst)
+        ));
+        tokio::fs::write(&path, content).await.unwrap();
+        let peers = FileDiscovery::new(path.to_string_lossy().to_string())
+            .list_peers()
+            .await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        peers
+    }
+
+    #[tokio::test]
+    async fn peers_are_read_and_resolved() {
+        let peers = list(
+            r#"[{"node_id": 1, "addr": "10.0.0.1:9000"}, {"node_id": 2, "addr": "localhost:9000"}]"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(peers[0].node_id, 1);
+        assert_eq!(peers[0].addr, "10.0.0.1:9000".parse().unwrap());
+        assert_eq!(peers[1].node_id, 2);
+        assert!(peers[1].addr.ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn malformed_entries_fail_the_poll() {
+        assert!(list(r#"[{"addr": "10.0.0.1:9000"}]"#).await.is_err());
+        assert!(list(r#"[{"node_id": 1}]"#).await.is_err());
+        assert!(list(r#"[{"node_id": 1, "addr": "10.0.0.1"}]"#)
+            .await
+            .is_err());
+        assert!(
+            list(r#"[{"node_id": 4294967297, "addr": "10.0.0.1:9000"}]"#)
+                .await
+                .is_err()
+        );
+        assert!(list("not json").await.is_err());
+        let missing = FileDiscovery::new(String::from("/nonexistent/peers.json"));
+        assert!(missing.list_peers().await.is_err());
+    }
+}
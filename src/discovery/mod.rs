@@ -0,0 +1,472 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::auth::BearerToken;
+use crate::conf_manager::ConfigurationManager;
+use crate::paxos::{ConnectionStatus, Membership, NodeConfig, PaxosService};
+use crate::proposer::Proposer;
+
+mod dns;
+mod eureka;
+mod file;
+mod static_peers;
+
+pub use dns::{DnsDiscovery, DnsRecord};
+pub use eureka::EurekaDiscovery;
+pub use file::FileDiscovery;
+pub use static_peers::StaticDiscovery;
+
+pub type DiscoveryError = Box<dyn Error + Send + Sync>;
+
+/// A cluster member as reported by a discovery backend.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Peer {
+    pub node_id: i32,
+    pub addr: SocketAddr,
+}
+
+/// Serving status announced to the discovery backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceStatus {
+    Starting,
+    OutOfService,
+    Up,
+}
+
+impl InstanceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InstanceStatus::Starting => "STARTING",
+            InstanceStatus::OutOfService => "OUT_OF_SERVICE",
+            InstanceStatus::Up => "UP",
+        }
+    }
+}
+
+/// Service discovery backend used to announce this node and find its peers.
+#[tonic::async_trait]
+pub trait Discovery: Send + Sync + 'static {
+    async fn register(&self) -> Result<(), DiscoveryError>;
+
+    async fn deregister(&self) -> Result<(), DiscoveryError>;
+
+    async fn heartbeat(&self) -> Result<(), DiscoveryError>;
+
+    /// Announces whether the node is ready to serve. Backends without a
+    /// notion of instance status ignore it.
+    async fn set_status(&self, _status: InstanceStatus) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    /// Publishes whether this node currently leads the cluster.
+    async fn set_leader(&self, _leader: bool) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn list_peers(&self) -> Result<Vec<Peer>, DiscoveryError>;
+
+    /// Polls `list_peers` every `interval` and sends the peer set each time
+    /// it changes. Backends with push notifications can override this.
+    fn watch(self: Arc<Self>, interval: Duration) -> mpsc::Receiver<Vec<Peer>> {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut known: Option<Vec<Peer>> = None;
+            loop {
+                ticker.tick().await;
+                let mut peers = match self.list_peers().await {
+                    Ok(peers) => peers,
+                    Err(e) => {
+                        eprintln!("peer discovery failed {}", e);
+                        continue;
+                    }
+                };
+                peers.sort();
+                if known.as_ref() != Some(&peers) {
+                    known = Some(peers.clone());
+                    if sender.send(peers).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// Builds the backend selected by `DISCOVERY`, failing on an unknown
+/// backend or settings it is missing.
+pub fn from_config(
+    conf_manager: &ConfigurationManager,
+    token: BearerToken,
+) -> Result<Arc<dyn Discovery>, DiscoveryError> {
+    let discovery: Arc<dyn Discovery> = match conf_manager.get_discovery() {
+        "static" => Arc::new(StaticDiscovery::new(
+            conf_manager
+                .get_static_peers()
+                .iter()
+                .map(|(node_id, addr)| Peer {
+                    node_id: *node_id,
+                    addr: *addr,
+                })
+                .collect(),
+        )),
+        "dns" => {
+            let name = conf_manager
+                .get_dns_name()
+                .ok_or("DNS_NAME is required by dns discovery")?;
+            let record = match conf_manager.get_dns_record() {
+                "srv" => DnsRecord::Srv,
+                // a records are identified with a plaintext status call
+                "a" if conf_manager.get_tls_cert().is_some() => {
+                    return Err("DNS_RECORD=a cannot identify peers over TLS, use srv".into())
+                }
+                "a" => DnsRecord::A(*conf_manager.get_host_port()),
+                other => return Err(format!("DNS_RECORD must be srv or a, got {other}").into()),
+            };
+            Arc::new(DnsDiscovery::new(String::from(name), record, token)?)
+        }
+        "file" => Arc::new(FileDiscovery::new(String::from(
+            conf_manager
+                .get_peers_file()
+                .ok_or("PEERS_FILE is required by file discovery")?,
+        ))),
+        "eureka" => Arc::new(EurekaDiscovery::new(conf_manager)),
+        other => return Err(format!("unknown DISCOVERY backend {other}").into()),
+    };
+    Ok(discovery)
+}
+
+/// Seeds `clusters` with the peers known at boot, before any consensus is
+/// possible. Later changes go through the leader, see `spawn_reconciler`.
+pub async fn seed(clusters: &Mutex<Membership>, peers: &[Peer]) {
+    let mut clusters = clusters.lock().await;
+    for peer in peers {
+        match clusters.get_mut(&peer.node_id) {
+            Some(node) => node.set_addr(peer.addr),
+            None => {
+                println!("node {} discovered at {}", peer.node_id, peer.addr);
+                clusters.insert(
+                    peer.node_id,
+                    NodeConfig::new(peer.node_id, peer.addr, ConnectionStatus::Active),
+                );
+            }
+        }
+    }
+}
+
+/// Hands every change in the discovered peer set to the leader, which adds
+/// new peers through consensus. Peers missing from the result are kept: a
+/// node dropping out of the registry is a health problem, not a removal.
+pub fn spawn_reconciler(
+    discovery: Arc<dyn Discovery>,
+    paxos: Arc<PaxosService>,
+    interval: Duration,
+) {
+    let mut changes = discovery.watch(interval);
+    tokio::spawn(async move {
+        while let Some(peers) = changes.recv().await {
+            paxos.reconcile_discovered(&peers).await;
+        }
+    });
+}
+
+/// Registers the node and keeps its lease alive every `interval`.
+///
+/// The node is announced `OUT_OF_SERVICE` until it has joined the cluster
+/// and applied everything its peers have, then `UP`. Failed calls are
+/// retried with exponential backoff capped at `interval`.
+pub fn spawn_heartbeat(
+    discovery: Arc<dyn Discovery>,
+    node_id: i32,
+    clusters: Arc<Mutex<Membership>>,
+    proposer: Arc<Mutex<Proposer>>,
+    leader_id: Arc<Mutex<Option<i32>>>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut backoff = Backoff::new(interval);
+        while let Err(e) = discovery.register().await {
+            let delay = backoff.next();
+            eprintln!("registration failed, retrying in {:?} {}", delay, e);
+            tokio::time::sleep(delay).await;
+        }
+        println!("instance registered");
+        backoff.reset();
+
+        let leader = *leader_id.lock().await == Some(node_id);
+        if let Err(e) = discovery.set_leader(leader).await {
+            eprintln!("leader metadata update failed {}", e);
+        }
+
+        let mut status = InstanceStatus::Starting;
+        loop {
+            let desired = if is_in_service(node_id, &clusters, &proposer, &leader_id).await {
+                InstanceStatus::Up
+            } else {
+                InstanceStatus::OutOfService
+            };
+            if desired != status {
+                match discovery.set_status(desired).await {
+                    Ok(()) => {
+                        println!(
+                            "instance status {} -> {}",
+                            status.as_str(),
+                            desired.as_str()
+                        );
+                        status = desired;
+                    }
+                    Err(e) => eprintln!("status update to {} failed {}", desired.as_str(), e),
+                }
+            }
+
+            match discovery.heartbeat().await {
+                Ok(()) => {
+                    backoff.reset();
+                    tokio::time::sleep(interval).await;
+                }
+                Err(e) => {
+                    let delay = backoff.next();
+                    eprintln!("heartbeat failed, retrying in {:?} {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    })
+}
+
+/// A node is in service once it knows a reachable leader and has applied at
+/// least as far as every active peer.
+async fn is_in_service(
+    node_id: i32,
+    clusters: &Mutex<Membership>,
+    proposer: &Mutex<Proposer>,
+    leader_id: &Mutex<Option<i32>>,
+) -> bool {
+    let leader_id = match *leader_id.lock().await {
+        Some(leader_id) => leader_id,
+        None => return false,
+    };
+    let applied_slot = proposer.lock().await.get_last_applied_slot();
+
+    let clusters = clusters.lock().await;
+    let joined = leader_id == node_id
+        || clusters
+            .get(&leader_id)
+            .is_some_and(|node| node.get_status() == ConnectionStatus::Active);
+    let caught_up = clusters
+        .values()
+        .filter(|node| node.get_status() == ConnectionStatus::Active)
+        .all(|node| node.get_applied_slot() <= applied_slot);
+
+    joined && caught_up
+}
+
+struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_secs(1);
+
+    fn new(max: Duration) -> Self {
+        Self {
+            current: Self::INITIAL.min(max),
+            max,
+        }
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = self.current.saturating_mul(2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.current = Self::INITIAL.min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::proposal::Proposal;
+    use crate::state_machine::KvStateMachine;
+    use crate::store::MemoryStore;
+
+    fn error(vars: &[(&str, &str)]) -> String {
+        let conf_manager = ConfigurationManager::from_vars(vars).unwrap();
+        match from_config(&conf_manager, BearerToken::default()) {
+            Ok(_) => panic!("{:?} was accepted", vars),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn missing_settings_are_reported() {
+        assert!(error(&[("DISCOVERY", "zookeeper")]).contains("zookeeper"));
+        assert!(error(&[("DISCOVERY", "dns")]).contains("DNS_NAME"));
+        assert!(error(&[("DISCOVERY", "file")]).contains("PEERS_FILE"));
+        let dns = [("DISCOVERY", "dns"), ("DNS_NAME", "veresiye")];
+        assert!(error(&[dns[0], dns[1], ("DNS_RECORD", "mx")]).contains("mx"));
+        let tls = [
+            ("DNS_RECORD", "a"),
+            ("TLS_CERT", "cert.pem"),
+            ("TLS_KEY", "key.pem"),
+            ("TLS_CA", "ca.pem"),
+        ];
+        assert!(error(&[&dns[..], &tls[..]].concat()).contains("TLS"));
+    }
+
+    #[tokio::test]
+    async fn configured_backends_are_built() {
+        let vars: [&[(&str, &str)]; 3] = [
+            &[("PEERS", "1@127.0.0.1:9001")],
+            &[("DISCOVERY", "file"), ("PEERS_FILE", "peers.json")],
+            &[("DISCOVERY", "dns"), ("DNS_NAME", "veresiye")],
+        ];
+        for vars in vars {
+            let conf_manager = ConfigurationManager::from_vars(vars).unwrap();
+            assert!(from_config(&conf_manager, BearerToken::default()).is_ok());
+        }
+
+        let conf_manager = ConfigurationManager::from_vars(vars[0]).unwrap();
+        let discovery = from_config(&conf_manager, BearerToken::default()).unwrap();
+        let peers = discovery.list_peers().await.unwrap();
+        assert_eq!(peers[0].addr, "127.0.0.1:9001".parse().unwrap());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_interval() {
+        let mut backoff = Backoff::new(Duration::from_secs(10));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+
+        let mut short = Backoff::new(Duration::from_millis(500));
+        assert_eq!(short.next(), Duration::from_millis(500));
+        assert_eq!(short.next(), Duration::from_millis(500));
+    }
+
+    fn proposer(applied_slot: i64) -> Mutex<Proposer> {
+        let mut proposer =
+            Proposer::new(Box::new(KvStateMachine::new(Box::new(MemoryStore::new()))));
+        for slot in 1..=applied_slot {
+            proposer.commit(Proposal::new(1, vec![]).with_slot(slot));
+        }
+        Mutex::new(proposer)
+    }
+
+    fn peer(node_id: i32, applied_slot: i64, status: ConnectionStatus) -> NodeConfig {
+        let addr = SocketAddr::from(([10, 0, 0, node_id as u8], 9000));
+        let mut node = NodeConfig::new(node_id, addr, ConnectionStatus::Active);
+        node.record_success(Duration::from_millis(1), applied_slot);
+        if status != ConnectionStatus::Active {
+            node.record_failure(1);
+        }
+        node
+    }
+
+    async fn in_service(leader_id: Option<i32>, peers: Vec<NodeConfig>) -> bool {
+        let clusters: Membership = peers
+            .into_iter()
+            .map(|node| (node.get_node_id(), node))
+            .collect();
+        is_in_service(
+            1,
+            &Mutex::new(clusters),
+            &proposer(10),
+            &Mutex::new(leader_id),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn in_service_once_joined_and_caught_up() {
+        assert!(!in_service(None, vec![]).await);
+        assert!(in_service(Some(1), vec![]).await);
+        assert!(in_service(Some(2), vec![peer(2, 10, ConnectionStatus::Active)]).await);
+
+        // leader not reachable from here
+        assert!(!in_service(Some(2), vec![peer(2, 10, ConnectionStatus::Unreachable)]).await);
+        assert!(!in_service(Some(2), vec![]).await);
+        // an active peer applied more than this node
+        let behind = vec![
+            peer(2, 10, ConnectionStatus::Active),
+            peer(3, 11, ConnectionStatus::Active),
+        ];
+        assert!(!in_service(Some(2), behind).await);
+        let lost_ahead = vec![
+            peer(2, 10, ConnectionStatus::Active),
+            peer(3, 11, ConnectionStatus::Unreachable),
+        ];
+        assert!(in_service(Some(2), lost_ahead).await);
+    }
+
+    /// Fails the first `register_failures` registrations and records the
+    /// statuses announced after that.
+    #[derive(Default)]
+    struct FlakyRegistry {
+        register_failures: AtomicU32,
+        registrations: AtomicU32,
+        statuses: std::sync::Mutex<Vec<InstanceStatus>>,
+    }
+
+    #[tonic::async_trait]
+    impl Discovery for FlakyRegistry {
+        async fn register(&self) -> Result<(), DiscoveryError> {
+            self.registrations.fetch_add(1, Ordering::SeqCst);
+            let failures = self.register_failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.register_failures.store(failures - 1, Ordering::SeqCst);
+                return Err("registry unavailable".into());
+            }
+            Ok(())
+        }
+
+        async fn deregister(&self) -> Result<(), DiscoveryError> {
+            Ok(())
+        }
+
+        async fn heartbeat(&self) -> Result<(), DiscoveryError> {
+            Ok(())
+        }
+
+        async fn set_status(&self, status: InstanceStatus) -> Result<(), DiscoveryError> {
+            self.statuses.lock().unwrap().push(status);
+            Ok(())
+        }
+
+        async fn list_peers(&self) -> Result<Vec<Peer>, DiscoveryError> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_retries_registration_then_reports_up() {
+        let registry = Arc::new(FlakyRegistry::default());
+        registry.register_failures.store(2, Ordering::SeqCst);
+        let heartbeat = spawn_heartbeat(
+            registry.clone(),
+            1,
+            Arc::new(Mutex::new(Membership::new())),
+            Arc::new(proposer(1)),
+            Arc::new(Mutex::new(Some(1))),
+            Duration::from_millis(10),
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        heartbeat.abort();
+
+        assert_eq!(registry.registrations.load(Ordering::SeqCst), 3);
+        assert_eq!(*registry.statuses.lock().unwrap(), vec![InstanceStatus::Up]);
+    }
+}
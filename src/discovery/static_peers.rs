@@ -0,0 +1,31 @@
+use super::{Discovery, DiscoveryError, Peer};
+
+/// Fixed membership taken from `PEERS`; nothing is announced anywhere.
+pub struct StaticDiscovery {
+    peers: Vec<Peer>,
+}
+
+impl StaticDiscovery {
+    pub fn new(peers: Vec<Peer>) -> Self {
+        Self { peers }
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for StaticDiscovery {
+    async fn register(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn deregister(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn heartbeat(&self) -> Result<(), DiscoveryError> {
+        Ok(())
+    }
+
+    async fn list_peers(&self) -> Result<Vec<Peer>, DiscoveryError> {
+        Ok(self.peers.clone())
+    }
+}
@@ -0,0 +1,118 @@
+use serde_json::{json, Value};
+
+use crate::proto::KvEntry;
+use crate::state_machine::KvStateMachine;
+
+/// Lines sent per export chunk and per import chunk.
+pub const LINES_PER_CHUNK: usize = 1000;
+
+/// An import batch is proposed once it holds this many keys...
+pub const MAX_BATCH_KEYS: usize = 1000;
+
+/// ...or this many bytes of keys and values, whichever comes first.
+pub const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// One exported key as a JSON line.
+pub fn to_line(entry: &KvEntry) -> String {
+    json!({
+        "key": entry.key,
+        "value": entry.value,
+        "revision": entry.revision,
+    })
+    .to_string()
+}
+
+/// Parses an exported line into its key and value. The revision is
+/// accepted but ignored, imported keys get the slot of their batch.
+pub fn parse_line(line: &str) -> Result<(String, String), String> {
+    let line: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let key = match line.get("key") {
+        Some(Value::String(key)) => key.clone(),
+        _ => return Err(String::from("key is missing or not a string")),
+    };
+    let value = match line.get("value") {
+        Some(Value::String(value)) => value.clone(),
+        _ => return Err(String::from("value is missing or not a string")),
+    };
+    if KvStateMachine::is_reserved(&key) {
+        return Err(format!("key {:?} is reserved", key));
+    }
+    Ok((key, value))
+}
+
+/// Collects imported pairs into batches bounded by `MAX_BATCH_KEYS` and
+/// `MAX_BATCH_BYTES`.
+#[derive(Default)]
+pub struct Batcher {
+    pairs: Vec<(String, String)>,
+    bytes: usize,
+}
+
+impl Batcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a pair and returns the batch once it is full.
+    pub fn push(&mut self, key: String, value: String) -> Option<Vec<(String, String)>> {
+        self.bytes += key.len() + value.len();
+        self.pairs.push((key, value));
+        if self.pairs.len() >= MAX_BATCH_KEYS || self.bytes >= MAX_BATCH_BYTES {
+            return self.flush();
+        }
+        None
+    }
+
+    /// Returns whatever is left, `None` when empty.
+    pub fn flush(&mut self) -> Option<Vec<(String, String)>> {
+        if self.pairs.is_empty() {
+            return None;
+        }
+        self.bytes = 0;
+        Some(std::mem::take(&mut self.pairs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exported_line_parses_back() {
+        let entry = KvEntry {
+            key: String::from("user/\"1\""),
+            value: String::from("a\nb"),
+            revision: 12,
+        };
+        let (key, value) = parse_line(&to_line(&entry)).unwrap();
+        assert_eq!(key, entry.key);
+        assert_eq!(value, entry.value);
+    }
+
+    #[test]
+    fn malformed_and_reserved_lines_are_rejected() {
+        assert!(parse_line("not json").is_err());
+        assert!(parse_line(r#"{"key": "k"}"#).is_err());
+        assert!(parse_line(r#"{"key": 1, "value": "v"}"#).is_err());
+        assert!(parse_line(r#"{"key": "\u0000rev/k", "value": "1"}"#).is_err());
+    }
+
+    #[test]
+    fn batches_split_on_key_count_and_size() {
+        let mut batcher = Batcher::new();
+        for i in 0..MAX_BATCH_KEYS - 1 {
+            assert!(batcher.push(i.to_string(), String::new()).is_none());
+        }
+        assert_eq!(
+            batcher
+                .push(String::from("last"), String::new())
+                .unwrap()
+                .len(),
+            MAX_BATCH_KEYS
+        );
+        assert!(batcher.flush().is_none());
+
+        let big = "x".repeat(MAX_BATCH_BYTES);
+        assert_eq!(batcher.push(String::from("big"), big).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::connection::ConnectionManager;
+use crate::paxos::Membership;
+use crate::proto::PingRequest;
+
+/// Periodically pings every peer in `clusters` and keeps their
+/// `ConnectionStatus`, last-seen time and round-trip time up to date.
+pub struct HealthChecker {
+    node_id: i32,
+    clusters: Arc<Mutex<Membership>>,
+    connections: Arc<ConnectionManager>,
+    interval: Duration,
+    unreachable_after: u32,
+}
+
+impl HealthChecker {
+    pub fn new(
+        node_id: i32,
+        clusters: Arc<Mutex<Membership>>,
+        connections: Arc<ConnectionManager>,
+        interval: Duration,
+        unreachable_after: u32,
+    ) -> Self {
+        Self {
+            node_id,
+            clusters,
+            connections,
+            interval,
+            unreachable_after: unreachable_after.max(1),
+        }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.interval);
+            loop {
+                ticker.tick().await;
+                self.check_peers().await;
+            }
+        })
+    }
+
+    async fn check_peers(&self) {
+        // ping without holding the cluster lock, then apply results in one go
+        let peers: Vec<_> = self
+            .clusters
+            .lock()
+            .await
+            .values()
+            .filter(|node| node.get_node_id() != self.node_id)
+            .map(|node| (node.get_node_id(), node.get_addr()))
+            .collect();
+
+        let mut results = vec![];
+        for (peer_id, addr) in peers {
+            let started = Instant::now();
+            let reply = match self.connections.get_probe_client(peer_id, addr).await {
+                Ok(mut client) => match client.ping(PingRequest {}).await {
+                    Ok(response) => Some(response.into_inner()).filter(|reply| reply.health),
+                    Err(_) => None,
+                },
+                Err(_) => None,
+            };
+
+            if reply.is_some() {
+                self.connections.report_success(peer_id).await;
+            } else {
+                self.connections.report_failure(peer_id).await;
+            }
+            results.push((peer_id, reply, started.elapsed()));
+        }
+
+        let mut clusters = self.clusters.lock().await;
+        for (peer_id, reply, rtt) in results {
+            if let Some(node) = clusters.get_mut(&peer_id) {
+                match reply {
+                    Some(reply) => node.record_success(rtt, reply.last_applied_slot),
+                    None => node.record_failure(self.unreachable_after),
+                }
+            }
+        }
+    }
+}
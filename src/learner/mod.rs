@@ -1,21 +1,420 @@
-use veresiye::Veresiye;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 
-use crate::proposal::{self, Proposal};
+use crate::proposal::Proposal;
+use crate::proto::ClientSession;
+use crate::state_hash::{RangeHash, StateHasher};
+use crate::state_machine::StateMachine;
 
 pub struct Learner {
-    database: Veresiye,
+    state_machine: Box<dyn StateMachine>,
+    last_applied_slot: i64,
+    // chosen slots waiting for an earlier slot this node has not learned
+    pending: BTreeMap<i64, Proposal>,
+    sessions: HashMap<String, Session>,
+    hasher: StateHasher,
+}
+
+/// Latest command applied for a client and its result. Every replica builds
+/// the same table from the chosen log, so any leader can answer a retry.
+#[derive(Debug, Clone)]
+pub struct Session {
+    sequence: u64,
+    result: Vec<u8>,
 }
 
 impl Learner {
-    pub fn new(database: Veresiye) -> Self {
-        Self { database }
+    pub fn new(mut state_machine: Box<dyn StateMachine>) -> Self {
+        let sessions = match state_machine.load_sessions() {
+            Ok(sessions) => sessions
+                .into_iter()
+                .map(|session| {
+                    (
+                        session.client_id,
+                        Session::new(session.sequence, session.result),
+                    )
+                })
+                .collect(),
+            Err(e) => {
+                eprintln!("cannot load client sessions {}", e);
+                HashMap::new()
+            }
+        };
+        let last_applied_slot = state_machine.load_applied_slot().unwrap_or_else(|e| {
+            eprintln!("cannot load the applied slot {}", e);
+            0
+        });
+        Self {
+            state_machine,
+            last_applied_slot,
+            pending: BTreeMap::new(),
+            sessions,
+            hasher: StateHasher::new(),
+        }
+    }
+
+    /// Learns a chosen proposal. Slots are applied in order, one after the
+    /// other: a slot already applied is not applied again, and a slot after
+    /// a missing one waits for it. Returns the state machine's result, or
+    /// `None` when the proposal was not applied now.
+    pub fn insert(&mut self, proposal: Proposal) -> Option<Vec<u8>> {
+        let slot = proposal.get_slot();
+        if slot <= self.last_applied_slot {
+            if self.hasher.record(&proposal) {
+                println!("slot {} was already applied", slot);
+            } else {
+                eprintln!(
+                    "slot {} was already applied with a different value, the log diverged",
+                    slot
+                );
+            }
+            return None;
+        }
+        if slot > self.last_applied_slot + 1 {
+            println!(
+                "slot {} waits for slot {}",
+                slot,
+                self.last_applied_slot + 1
+            );
+            self.pending.insert(slot, proposal);
+            return None;
+        }
+        let result = self.apply(proposal)?;
+        self.apply_pending();
+        Some(result)
+    }
+
+    /// Whether chosen slots wait for one this node missed or failed to
+    /// apply. Only a re-sync from a peer gets the learner going again.
+    pub fn is_stalled(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    fn apply_pending(&mut self) {
+        while let Some(proposal) = self.pending.remove(&(self.last_applied_slot + 1)) {
+            if self.apply(proposal).is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Applies the next slot. A slot the state machine fails on stays
+    /// pending and is not applied past, `None` is returned.
+    fn apply(&mut self, proposal: Proposal) -> Option<Vec<u8>> {
+        let slot = proposal.get_slot();
+        let result = match self.apply_command(&proposal) {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("cannot apply slot {} {}, waiting for re-sync", slot, e);
+                self.pending.insert(slot, proposal);
+                return None;
+            }
+        };
+        self.last_applied_slot = slot;
+        self.hasher.record(&proposal);
+        if let Err(e) = self.state_machine.save_applied_slot(slot) {
+            eprintln!("cannot save applied slot {} {}", slot, e);
+        }
+        Some(result)
+    }
+
+    fn apply_command(&mut self, proposal: &Proposal) -> io::Result<Vec<u8>> {
+        // membership commands only occupy the slot, the state is untouched
+        if proposal.get_config().is_some() {
+            return Ok(vec![]);
+        }
+
+        // a retried command chosen twice is applied once
+        let request_id = match proposal.get_request_id() {
+            Some(request_id) => request_id,
+            None => {
+                return self
+                    .state_machine
+                    .apply(proposal.get_slot(), proposal.get_command())
+            }
+        };
+        if let Some(session) = self.sessions.get(request_id.get_client_id()) {
+            if request_id.get_sequence() <= session.sequence {
+                println!(
+                    "request {} of client {} already applied",
+                    request_id.get_sequence(),
+                    request_id.get_client_id()
+                );
+                return Ok(session.result.clone());
+            }
+        }
+        let result = self
+            .state_machine
+            .apply(proposal.get_slot(), proposal.get_command())?;
+        self.save_session(
+            request_id.get_client_id(),
+            Session {
+                sequence: request_id.get_sequence(),
+                result: result.clone(),
+            },
+        );
+        Ok(result)
+    }
+
+    fn save_session(&mut self, client_id: &str, session: Session) {
+        let saved = self.state_machine.save_session(&ClientSession {
+            client_id: client_id.to_string(),
+            sequence: session.sequence,
+            result: session.result.clone(),
+        });
+        if let Err(e) = saved {
+            eprintln!("cannot save session of client {} {}", client_id, e);
+        }
+        self.sessions.insert(client_id.to_string(), session);
+    }
+
+    pub fn get_last_applied_slot(&self) -> i64 {
+        self.last_applied_slot
+    }
+
+    pub fn get_session(&self, client_id: &str) -> Option<Session> {
+        self.sessions.get(client_id).cloned()
+    }
+
+    pub fn query(&mut self, query: &[u8]) -> Vec<u8> {
+        self.state_machine.query(query)
+    }
+
+    pub fn snapshot(&mut self) -> io::Result<Vec<u8>> {
+        self.state_machine.snapshot()
+    }
+
+    pub fn get_sessions(&self) -> &HashMap<String, Session> {
+        &self.sessions
+    }
+
+    /// Hashes of the completed slot ranges applied here.
+    pub fn get_range_hashes(&self) -> Vec<RangeHash> {
+        self.hasher.get_ranges(self.last_applied_slot)
+    }
+
+    pub fn is_corrupted(&self) -> bool {
+        self.state_machine.is_corrupted()
+    }
+
+    /// Replaces the state with a backup taken at `applied_slot`.
+    pub fn restore(
+        &mut self,
+        applied_slot: i64,
+        state: &[u8],
+        sessions: HashMap<String, Session>,
+    ) -> io::Result<()> {
+        self.state_machine.restore(state)?;
+        self.sessions.clear();
+        for (client_id, session) in sessions {
+            self.save_session(&client_id, session);
+        }
+        self.state_machine.save_applied_slot(applied_slot)?;
+        self.last_applied_slot = applied_slot;
+        self.hasher.reset(applied_slot);
+        // slots learned while the backup was fetched follow it
+        self.pending = self.pending.split_off(&(applied_slot + 1));
+        self.apply_pending();
+        Ok(())
+    }
+}
+
+impl Session {
+    pub fn new(sequence: u64, result: Vec<u8>) -> Self {
+        Self { sequence, result }
+    }
+
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn get_result(&self) -> &[u8] {
+        &self.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::paxos::ConfigChange;
+    use crate::proposal::RequestId;
+    use crate::state_machine::KvStateMachine;
+    use crate::store::MemoryStore;
+
+    fn learner() -> Learner {
+        Learner::new(Box::new(KvStateMachine::new(Box::new(MemoryStore::new()))))
+    }
+
+    fn put(slot: i64, key: &str, value: &str) -> Proposal {
+        Proposal::new(1, KvStateMachine::set_command(key, value)).with_slot(slot)
+    }
+
+    fn read(learner: &mut Learner, key: &str) -> Option<String> {
+        KvStateMachine::decode_value(&learner.query(&KvStateMachine::get_query(key)))
+    }
+
+    /// Counts applied commands and returns the new count, fails on `fail`.
+    struct Counter(u8);
+
+    impl StateMachine for Counter {
+        fn apply(&mut self, _slot: i64, command: &[u8]) -> io::Result<Vec<u8>> {
+            if command == b"fail" {
+                return Err(io::Error::other("disk full"));
+            }
+            self.0 += 1;
+            Ok(vec![self.0])
+        }
+
+        fn query(&mut self, _query: &[u8]) -> Vec<u8> {
+            vec![self.0]
+        }
+
+        fn snapshot(&mut self) -> io::Result<Vec<u8>> {
+            Ok(vec![self.0])
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> io::Result<()> {
+            self.0 = snapshot[0];
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn applies_values_and_tracks_slot() {
+        let mut learner = learner();
+        learner.insert(put(1, "k", "v1"));
+        learner.insert(put(2, "k", "v2"));
+
+        assert_eq!(read(&mut learner, "k"), Some(String::from("v2")));
+        assert_eq!(learner.get_last_applied_slot(), 2);
+    }
+
+    #[test]
+    fn applied_slot_is_not_applied_again() {
+        let mut learner = Learner::new(Box::new(Counter(0)));
+        assert_eq!(
+            learner.insert(Proposal::new(1, vec![]).with_slot(1)),
+            Some(vec![1])
+        );
+        assert_eq!(learner.insert(Proposal::new(2, vec![]).with_slot(1)), None);
+        assert_eq!(learner.query(&[]), vec![1]);
+    }
+
+    #[test]
+    fn failed_slot_stalls_the_learner() {
+        let mut learner = Learner::new(Box::new(Counter(0)));
+        learner.insert(Proposal::new(1, vec![]).with_slot(1));
+        let failing = Proposal::new(1, b"fail".to_vec()).with_slot(2);
+        assert_eq!(learner.insert(failing), None);
+        assert_eq!(learner.insert(Proposal::new(1, vec![]).with_slot(3)), None);
+
+        assert_eq!(learner.get_last_applied_slot(), 1);
+        assert_eq!(learner.query(&[]), vec![1]);
+        assert!(learner.is_stalled());
+    }
+
+    #[test]
+    fn slots_wait_for_the_missing_one() {
+        let mut learner = learner();
+        learner.insert(put(1, "k", "v1"));
+        assert_eq!(learner.insert(put(3, "k", "v3")), None);
+        assert_eq!(learner.get_last_applied_slot(), 1);
+        assert!(learner.is_stalled());
+
+        learner.insert(put(2, "k", "v2"));
+        assert_eq!(read(&mut learner, "k"), Some(String::from("v3")));
+        assert_eq!(learner.get_last_applied_slot(), 3);
+        assert!(!learner.is_stalled());
+    }
+
+    #[test]
+    fn restore_applies_the_slots_learned_after_the_backup() {
+        let mut learner = learner();
+        learner.insert(put(1, "k", "v1"));
+        learner.insert(put(4, "k", "stale"));
+        learner.insert(put(6, "k", "v6"));
+
+        let state = KvStateMachine::new(Box::new(MemoryStore::new()))
+            .snapshot()
+            .unwrap();
+        learner.restore(5, &state, HashMap::new()).unwrap();
+        assert_eq!(learner.get_last_applied_slot(), 6);
+        assert_eq!(read(&mut learner, "k"), Some(String::from("v6")));
+        assert!(!learner.is_stalled());
+    }
+
+    #[test]
+    fn repeated_request_is_applied_once() {
+        let mut learner = learner();
+        let request_id = || Some(RequestId::new(String::from("client"), 7));
+        learner.insert(put(1, "balance", "100").with_request_id(request_id()));
+        learner.insert(put(2, "balance", "200").with_request_id(request_id()));
+
+        assert_eq!(read(&mut learner, "balance"), Some(String::from("100")));
+        assert_eq!(learner.get_session("client").unwrap().get_sequence(), 7);
+        assert_eq!(learner.get_last_applied_slot(), 2);
     }
 
-    pub fn insert(&mut self, proposal: Proposal) {
-        self.database.set(proposal.get_key(), proposal.get_value());
+    #[test]
+    fn repeated_request_returns_the_cached_result() {
+        let mut learner = Learner::new(Box::new(Counter(0)));
+        let increment = |slot: i64| {
+            Proposal::new(1, vec![])
+                .with_slot(slot)
+                .with_request_id(Some(RequestId::new(String::from("client"), 1)))
+        };
+
+        assert_eq!(learner.insert(increment(1)), Some(vec![1]));
+        assert_eq!(learner.insert(increment(2)), Some(vec![1]));
+        assert_eq!(learner.query(&[]), vec![1]);
+    }
+
+    #[test]
+    fn sessions_survive_a_restart() {
+        let mut learner = learner();
+        let request_id = || Some(RequestId::new(String::from("client"), 4));
+        learner.insert(put(1, "balance", "100").with_request_id(request_id()));
+
+        // the state as a restarted node finds it in its store
+        let mut machine = KvStateMachine::new(Box::new(MemoryStore::new()));
+        machine.restore(&learner.snapshot().unwrap()).unwrap();
+        let mut restarted = Learner::new(Box::new(machine));
+        assert_eq!(restarted.get_session("client").unwrap().get_sequence(), 4);
+        assert_eq!(restarted.get_last_applied_slot(), 1);
+        assert_eq!(restarted.insert(put(1, "balance", "100")), None);
+
+        restarted.insert(put(2, "balance", "200").with_request_id(request_id()));
+        assert_eq!(read(&mut restarted, "balance"), Some(String::from("100")));
     }
 
-    pub fn read(&mut self, key: String) -> Option<String> {
-        self.database.get(&key)
+    #[test]
+    fn restored_sessions_replace_the_old_ones() {
+        let mut learner = learner();
+        learner
+            .insert(put(1, "k", "v").with_request_id(Some(RequestId::new(String::from("old"), 1))));
+
+        let sessions = HashMap::from([(String::from("new"), Session::new(2, vec![]))]);
+        let state = KvStateMachine::new(Box::new(MemoryStore::new()))
+            .snapshot()
+            .unwrap();
+        learner.restore(5, &state, sessions).unwrap();
+        assert!(learner.get_session("old").is_none());
+        assert_eq!(learner.get_session("new").unwrap().get_sequence(), 2);
+
+        let mut machine = KvStateMachine::new(Box::new(MemoryStore::new()));
+        machine.restore(&learner.snapshot().unwrap()).unwrap();
+        assert_eq!(machine.load_sessions().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn config_commands_leave_the_state_untouched() {
+        let mut learner = learner();
+        let change = ConfigChange::RemoveVoter { node_id: 2 };
+        learner.insert(put(1, "k", "v").with_config(Some(change)));
+
+        assert_eq!(read(&mut learner, "k"), None);
+        assert_eq!(learner.get_last_applied_slot(), 1);
     }
 }
@@ -1,32 +1,59 @@
-#![allow(warnings)]
+// every rpc handler and the helpers they call fail with a tonic::Status
+#![allow(clippy::result_large_err)]
+// generated code names streaming responses after the rpc, e.g. backupStream
+#[allow(non_camel_case_types)]
 mod proto {
     tonic::include_proto!("paxos");
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
         tonic::include_file_descriptor_set!("paxos_descriptor");
 }
 use conf_manager::ConfigurationManager;
-use port_check::*;
-use reqwest::header::HeaderMap;
-use serde_json::json;
+use connection::ConnectionManager;
+use data_dir::DataDir;
+use health::HealthChecker;
 use std::env;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinSet;
 
-use acceptor::{Accept_Message, Acceptor, Acceptor_Status, Promise};
-use paxos::{NodeConfig, PaxosService};
-use proposal::Proposal;
+use acceptor::Acceptor;
+use auth::{AuthConfig, Authenticator, BearerToken};
+use paxos::{Membership, PaxosService};
 use proposer::Proposer;
-use proto::paxos_server::{Paxos, PaxosServer};
-use tonic::transport::{Channel, Server};
+use proto::cluster_admin_server::ClusterAdminServer;
+use proto::kv_client_server::KvClientServer;
+use proto::paxos_peer_server::PaxosPeerServer;
+use state_machine::KvStateMachine;
+use store::VeresiyeStore;
+use tls::TlsManager;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::Server;
 use tower_http::cors::CorsLayer;
 
 mod acceptor;
+mod auth;
+mod backup;
+mod checksum;
+mod cli;
 mod conf_manager;
+mod connection;
+mod data_dir;
+mod discovery;
+mod export;
+mod health;
 mod learner;
 mod paxos;
 mod proposal;
 mod proposer;
+mod quorum;
+mod state_hash;
+mod state_machine;
+mod store;
+mod tls;
 
 /*
  *
@@ -46,6 +73,10 @@ mod proposer;
 */
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
     let conf_manager = ConfigurationManager::new(
         env::var("EUREKA_ADDR"),
         env::var("EUREKA_PORT"),
@@ -53,13 +84,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         env::var("HOST_ADDR"),
         env::var("HOST_PORT"),
         env::var("APP_ID"),
-    );
+        env::var("HEALTH_INTERVAL_MS"),
+        env::var("HEALTH_UNREACHABLE_AFTER"),
+        env::var("PEERS"),
+        env::var("DISCOVERY"),
+        env::var("DISCOVERY_INTERVAL_MS"),
+        env::var("DNS_NAME"),
+        env::var("DNS_RECORD"),
+        env::var("PEERS_FILE"),
+        env::var("EUREKA_RENEWAL_SECS"),
+        env::var("NODE_ROLE"),
+        env::var("EVICT_AFTER_SECS"),
+        env::var("MIN_CLUSTER_SIZE"),
+        env::var("DATA_DIR"),
+        env::var("CLUSTER_ID"),
+        env::var("RESTORE_FROM"),
+        env::var("VERIFY_INTERVAL_SECS"),
+        env::var("TLS_CERT"),
+        env::var("TLS_KEY"),
+        env::var("TLS_CA"),
+        env::var("TLS_CLIENT_AUTH"),
+        env::var("AUTH_FILE"),
+        env::var("CLIENT_PORT"),
+        env::var("ADMIN_PORT"),
+        env::var("BACKUP_DIR"),
+    )?;
 
-    //register instance at starting
-    match conf_manager.eureka_registration().send().await {
-        Ok(resp) => println!("instance registered successfuly {:?}", resp),
-        Err(e) => panic!("{e}"),
-    }
+    let auth = match conf_manager.get_auth_file() {
+        Some(path) => Some(Arc::new(AuthConfig::load(path)?)),
+        None => None,
+    };
+    // peers authenticate to each other with the cluster token
+    let cluster_token = BearerToken::new(auth.as_ref().map(|auth| auth.get_cluster_token()));
+    let discovery = discovery::from_config(&conf_manager, cluster_token.clone()).map_err(|e| {
+        format!(
+            "cannot set up {} discovery {}",
+            conf_manager.get_discovery(),
+            e
+        )
+    })?;
+    println!("using {} discovery", conf_manager.get_discovery());
 
     let addr = SocketAddr::new(
         IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
@@ -67,14 +131,133 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
     // let addr = "[::1]:54134".parse().unwrap();
     println!("Server is running on {}", addr);
+    // peers dial the advertised address, the bind address is not routable
+    let advertise_addr = match (
+        conf_manager.get_host_address(),
+        *conf_manager.get_host_port(),
+    )
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.next())
+    {
+        Ok(Some(advertise_addr)) if !advertise_addr.ip().is_unspecified() => advertise_addr,
+        Ok(_) | Err(_) => {
+            return Err(format!(
+                "HOST_ADDR {} does not resolve to an address peers can reach, set it to this node's address",
+                conf_manager.get_host_address()
+            )
+            .into())
+        }
+    };
     // paxos configuration
-    let proposer = Arc::new(Mutex::new(Proposer::new(String::from("./data"))));
-    let clusters: Arc<Mutex<Vec<NodeConfig>>> = Arc::new(Mutex::new(vec![]));
-    let channels: Arc<Mutex<Vec<Channel>>> = Arc::new(Mutex::new(vec![]));
-    let leader_id: Option<i32> = Some(1 as i32);
+    let mut data_dir = DataDir::open(
+        conf_manager.get_data_dir(),
+        conf_manager.get_cluster_id(),
+        *conf_manager.get_node_id(),
+    )?;
+    let store = VeresiyeStore::open(&data_dir.get_store_path().to_string_lossy())?;
+    let state_machine = KvStateMachine::new(Box::new(store));
+    let acceptor = Acceptor::open(&data_dir.get_acceptor_path())?;
+    let mut proposer = Proposer::new(Box::new(state_machine)).with_acceptor(acceptor);
+    // every node of the new cluster is seeded from the same backup
+    match conf_manager.get_restore_from() {
+        Some(path) if data_dir.is_fresh() => {
+            let backup = backup::read_file(std::path::Path::new(path))?;
+            backup::restore(&mut proposer, backup, conf_manager.get_cluster_id())?;
+        }
+        Some(path) => println!(
+            "data directory is already initialized, RESTORE_FROM {} ignored",
+            path
+        ),
+        None => {}
+    }
+    data_dir.initialize()?;
+    let proposer = Arc::new(Mutex::new(proposer));
+    let clusters: Arc<Mutex<Membership>> = Arc::new(Mutex::new(Membership::new()));
+    match discovery.list_peers().await {
+        Ok(peers) => discovery::seed(&clusters, &peers).await,
+        Err(e) => eprintln!("initial peer discovery failed {}", e),
+    }
+    let tls = match (
+        conf_manager.get_tls_cert(),
+        conf_manager.get_tls_key(),
+        conf_manager.get_tls_ca(),
+    ) {
+        (Some(cert), Some(key), Some(ca)) => {
+            println!("serving TLS, peers must present a node certificate");
+            Some(Arc::new(TlsManager::load(
+                cert,
+                key,
+                ca,
+                conf_manager.get_tls_require_client_cert(),
+            )?))
+        }
+        _ => None,
+    };
+    let connections = Arc::new(ConnectionManager::new(tls.clone(), cluster_token));
+    if let Some(tls) = &tls {
+        tls::spawn_reloader(tls.clone(), connections.clone());
+    }
+    let leader_id: Option<i32> = Some(1);
     let node_id: i32 = *conf_manager.get_node_id();
+    let role = conf_manager.get_node_role();
     // let leader_id: Option<i32> = None;
-    let paxos = PaxosService::new(node_id, addr, clusters, channels, proposer, leader_id);
+    HealthChecker::new(
+        node_id,
+        clusters.clone(),
+        connections.clone(),
+        Duration::from_millis(*conf_manager.get_health_interval_ms()),
+        *conf_manager.get_unreachable_after(),
+    )
+    .spawn();
+    let paxos = Arc::new(
+        PaxosService::new(
+            node_id,
+            conf_manager.get_cluster_id().to_string(),
+            advertise_addr,
+            role,
+            clusters.clone(),
+            connections,
+            proposer.clone(),
+            leader_id,
+            discovery.clone(),
+        )
+        .with_peer_verification(tls.is_some())
+        .with_auth(auth.is_some())
+        .with_backup_dir(conf_manager.get_backup_dir().map(PathBuf::from)),
+    );
+
+    discovery::spawn_reconciler(
+        discovery.clone(),
+        paxos.clone(),
+        Duration::from_millis(*conf_manager.get_discovery_interval_ms()),
+    );
+
+    paxos::spawn_resync(paxos.clone());
+
+    if *conf_manager.get_verify_interval_secs() > 0 {
+        paxos::spawn_verifier(
+            paxos.clone(),
+            Duration::from_secs(*conf_manager.get_verify_interval_secs()),
+        );
+    }
+
+    if let Some(evict_after_secs) = conf_manager.get_evict_after_secs() {
+        paxos::spawn_evictor(
+            paxos.clone(),
+            Duration::from_secs(evict_after_secs),
+            *conf_manager.get_min_cluster_size(),
+        );
+    }
+
+    //register instance at starting and keep the lease alive
+    let heartbeat = discovery::spawn_heartbeat(
+        discovery.clone(),
+        node_id,
+        clusters,
+        proposer,
+        paxos.get_leader_id(),
+        Duration::from_secs(*conf_manager.get_eureka_renewal_secs()),
+    );
 
     let reflection_service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
@@ -85,13 +268,91 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .layer(CorsLayer::new())
         .layer(tonic_web::GrpcWebLayer::new());
 
-    Server::builder()
-        .accept_http1(true)
-        .layer(layer)
-        .add_service(reflection_service)
-        .add_service(PaxosServer::new(paxos))
-        .serve(addr)
-        .await?;
+    // on SIGTERM: stop writes, hand leadership over, leave the registry
+    let shutdown = {
+        let paxos = paxos.clone();
+        let discovery = discovery.clone();
+        async move {
+            shutdown_signal().await;
+            println!("shutdown requested, draining writes");
+            paxos.drain(DRAIN_TIMEOUT).await;
+            paxos.hand_off_leadership().await;
+            heartbeat.abort();
+            match discovery.deregister().await {
+                Ok(()) => println!("instance deregistered"),
+                Err(e) => eprintln!("deregistration failed {}", e),
+            }
+        }
+    };
+
+    let authenticator = Authenticator::new(auth);
+    let peer_service = InterceptedService::new(
+        PaxosPeerServer::from_arc(paxos.clone()),
+        authenticator.clone(),
+    );
+    let client_service = InterceptedService::new(
+        KvClientServer::from_arc(paxos.clone()),
+        authenticator.clone(),
+    );
+    let admin_service = InterceptedService::new(ClusterAdminServer::from_arc(paxos), authenticator);
+
+    // the peer protocol stays on HOST_PORT, clients and admins may be moved
+    // to their own ports so it can be firewalled
+    let peer_port = *conf_manager.get_host_port();
+    let client_port = *conf_manager.get_client_port();
+    let admin_port = *conf_manager.get_admin_port();
+    let mut ports = vec![peer_port];
+    for port in [client_port, admin_port] {
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+
+    let (stop, stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown.await;
+        let _ = stop.send(true);
+    });
+
+    let mut servers = JoinSet::new();
+    for port in ports {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+        let router = Server::builder()
+            .accept_http1(true)
+            .layer(layer.clone())
+            .add_service(reflection_service.clone())
+            .add_optional_service((port == peer_port).then(|| peer_service.clone()))
+            .add_optional_service((port == client_port).then(|| client_service.clone()))
+            .add_optional_service((port == admin_port).then(|| admin_service.clone()));
+        let services: Vec<&str> = [
+            (peer_port, "peer"),
+            (client_port, "client"),
+            (admin_port, "admin"),
+        ]
+        .into_iter()
+        .filter(|(service_port, _)| *service_port == port)
+        .map(|(_, name)| name)
+        .collect();
+        println!("serving {} requests on {}", services.join(", "), addr);
+
+        let mut stopped = stopped.clone();
+        let shutdown = async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        };
+        match &tls {
+            Some(tls) => {
+                let incoming = tls::incoming(TcpListener::bind(addr).await?, tls.clone());
+                servers.spawn(router.serve_with_incoming_shutdown(incoming, shutdown));
+            }
+            None => {
+                servers.spawn(router.serve_with_shutdown(addr, shutdown));
+            }
+        }
+    }
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
+    println!("server stopped");
     Ok(())
 
     /*
@@ -114,33 +375,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     */
 }
 
-fn send_prepare(multi_paxos: &mut Vec<Proposer>, proposal: Proposal) -> Vec<Promise> {
-    println!("Sending prepare command each acceptor");
-    let mut responses: Vec<Promise> = vec![];
-    for paxos in multi_paxos.iter_mut() {
-        match paxos.prepare(proposal.clone()) {
-            Some(prom) => responses.push(prom),
-            None => continue,
-        }
-    }
-
-    responses
-}
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
-fn send_accept(multi_paxos: &mut Vec<Proposer>, proposal: Proposal) {
-    println!("Sending accept command each acceptor");
-    let mut responses: Vec<Accept_Message> = vec![];
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("cannot listen for SIGTERM");
 
-    for paxos in multi_paxos.iter_mut() {
-        match paxos.accept(proposal.clone()) {
-            Some(message) => {
-                if message.status == Acceptor_Status::Accepted {
-                    paxos.commit(proposal.clone());
-                }
-            }
-            None => eprintln!("Paxos return negative response"),
-        }
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
 }
-
-fn learner_commit() {}
@@ -0,0 +1,145 @@
+use tonic::{Request, Status};
+
+use super::PaxosService;
+use crate::auth::{self, Permission, Principal};
+
+impl PaxosService {
+    /// With auth on, fails unless the caller's principal has `permission`.
+    pub(super) fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        permission: Permission,
+    ) -> Result<(), Status> {
+        self.authorize_principal(auth::principal(request), permission)
+    }
+
+    /// Same as `authorize`, for handlers that consumed the request.
+    pub(super) fn authorize_principal(
+        &self,
+        principal: Option<&Principal>,
+        permission: Permission,
+    ) -> Result<(), Status> {
+        if !self.auth_enabled {
+            return Ok(());
+        }
+        let principal =
+            principal.ok_or_else(|| Status::unauthenticated("request was not authenticated"))?;
+        if principal.is_allowed(permission) {
+            return Ok(());
+        }
+        Err(Status::permission_denied(format!(
+            "{} may not {}",
+            principal.get_subject(),
+            permission
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tonic::Code;
+
+    use super::*;
+    use crate::auth::AuthConfig;
+    use crate::export;
+    use crate::proto::cluster_admin_server::ClusterAdmin;
+    use crate::proto::StatusRequest;
+
+    fn client(token: &str) -> Principal {
+        AuthConfig::parse(&json!({
+            "cluster_token": "peers",
+            "roles": {
+                "orders": {"grants": [{"prefix": "orders/", "access": "write"}]},
+                "ops": {"admin": true}
+            },
+            "tokens": [
+                {"token": "billing", "subject": "billing", "roles": ["orders"]},
+                {"token": "oncall", "subject": "oncall", "roles": ["ops"]}
+            ]
+        }))
+        .unwrap()
+        .authenticate(token)
+        .unwrap()
+    }
+
+    fn import(
+        service: &PaxosService,
+        principal: Option<&Principal>,
+        keys: &[&str],
+    ) -> Result<Vec<Vec<(String, String)>>, Status> {
+        let lines = keys
+            .iter()
+            .map(|key| json!({"key": key, "value": "v"}).to_string())
+            .collect();
+        service.import_lines(principal, &mut export::Batcher::new(), lines, &mut 0, 0)
+    }
+
+    #[test]
+    fn anything_goes_with_auth_off() {
+        let service = PaxosService::for_tests();
+        assert!(service
+            .authorize(&Request::new(()), Permission::Admin)
+            .is_ok());
+        assert!(import(&service, None, &["users/1"]).is_ok());
+    }
+
+    #[test]
+    fn principal_needs_the_permission() {
+        let service = PaxosService::for_tests().with_auth(true);
+        let status = service
+            .authorize(&Request::new(()), Permission::Admin)
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let oncall = client("oncall");
+        assert!(service
+            .authorize_principal(Some(&oncall), Permission::Admin)
+            .is_ok());
+        let status = service
+            .authorize_principal(Some(&client("billing")), Permission::Admin)
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn status_needs_admin() {
+        let service = PaxosService::for_tests().with_auth(true);
+        let request = |principal: Option<Principal>| {
+            let mut request = Request::new(StatusRequest {});
+            if let Some(principal) = principal {
+                request.extensions_mut().insert(principal);
+            }
+            request
+        };
+
+        let status = ClusterAdmin::status(&service, request(None))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = ClusterAdmin::status(&service, request(Some(client("billing"))))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(
+            ClusterAdmin::status(&service, request(Some(client("oncall"))))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn import_checks_every_key() {
+        let service = PaxosService::for_tests().with_auth(true);
+        let billing = client("billing");
+        assert!(import(&service, Some(&billing), &["orders/1", "orders/2"]).is_ok());
+
+        let status = import(&service, Some(&billing), &["orders/1", "users/1"]).unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        let status = import(&service, Some(&client("oncall")), &["orders/1"]).unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        let status = import(&service, None, &["orders/1"]).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(import(&service, Some(&Principal::Member), &["users/1"]).is_ok());
+    }
+}
@@ -0,0 +1,339 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use tonic::Status;
+
+use super::{ConnectionStatus, Membership, PaxosService};
+use crate::proposal::Proposal;
+use crate::proto::{LeaderRequest, PingRequest, ProposerRequest, TakeLeadershipRequest};
+use crate::quorum::Quorum;
+
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl PaxosService {
+    /// Records the current leader and publishes it to the discovery backend.
+    pub(super) async fn set_leader(&self, leader_id: i32) {
+        *self.leader_id.lock().await = Some(leader_id);
+        if let Err(e) = self.discovery.set_leader(leader_id == self.node_id).await {
+            eprintln!("leader metadata update failed {}", e);
+        }
+    }
+
+    /// Runs phase 1 with a ballot above everything this node has seen and,
+    /// once a majority promised, announces this node as leader to all peers.
+    pub(super) async fn elect(&self) -> bool {
+        let peers: Vec<_> = self
+            .clusters
+            .lock()
+            .await
+            .values()
+            .filter(|node| node.node_id != self.node_id && node.is_voter())
+            .map(|node| (node.node_id, node.addr))
+            .collect();
+        let quorum = Quorum::with_local(peers.len());
+
+        let _proposing = self.proposing.lock().await;
+        let (ballot, slot) = {
+            let mut proposer = self.proposer.lock().await;
+            let ballot = proposer.get_ballot() + 1;
+            proposer.set_last_seen_propose_id(ballot);
+            (ballot, proposer.get_next_slot())
+        };
+        println!("starting election with ballot {}", ballot);
+
+        let mut promises: usize = 0;
+        let proposal = Proposal::new(ballot, vec![]).with_slot(slot);
+        if self.proposer.lock().await.prepare(proposal).is_some() {
+            promises += 1;
+        }
+
+        for (peer_id, addr) in peers.iter() {
+            let mut client = match self.connections.get_client(*peer_id, *addr).await {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            let prepare = ProposerRequest {
+                proposal_id: ballot,
+                command: vec![],
+                slot,
+                config: None,
+                request_id: None,
+            };
+            match client.propose(prepare).await {
+                Ok(response) if response.get_ref().promised_proposal_id == ballot => promises += 1,
+                Ok(_) => {}
+                Err(e) => println!("node {} refused ballot {} {}", peer_id, ballot, e),
+            }
+        }
+
+        if !quorum.is_reached(promises) {
+            println!(
+                "election with ballot {} lost, {} promises",
+                ballot, promises
+            );
+            return false;
+        }
+
+        self.set_leader(self.node_id).await;
+        println!("elected leader with ballot {}", ballot);
+
+        for (peer_id, addr) in peers {
+            if let Ok(mut client) = self.connections.get_client(peer_id, addr).await {
+                let announcement = LeaderRequest {
+                    node_id: self.node_id,
+                    ballot,
+                };
+                if let Err(e) = client.announce_leader(announcement).await {
+                    println!("leader announcement to node {} failed {}", peer_id, e);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Rejects new writes and waits up to `timeout` for in-flight ones.
+    pub async fn drain(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        if self.wait_for_in_flight(timeout).await {
+            println!("in-flight writes drained");
+        } else {
+            eprintln!(
+                "{} writes still in flight after {:?}, giving up",
+                self.in_flight.load(Ordering::SeqCst),
+                timeout
+            );
+        }
+    }
+
+    async fn wait_for_in_flight(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    /// Moves leadership to `target`: stops proposing, waits for the target
+    /// to apply everything this node applied, then has it run phase 1.
+    pub(super) async fn transfer_leadership_to(&self, target: i32) -> Result<(), Status> {
+        if *self.leader_id.lock().await != Some(self.node_id) {
+            return Err(Status::failed_precondition(format!(
+                "node {} is not the leader",
+                self.node_id
+            )));
+        }
+        if target == self.node_id {
+            return Ok(());
+        }
+        let addr = self
+            .clusters
+            .lock()
+            .await
+            .get(&target)
+            .filter(|node| node.is_voter())
+            .map(|node| node.addr)
+            .ok_or_else(|| Status::not_found(format!("voter {} is not registered", target)))?;
+
+        if self.transferring.swap(true, Ordering::SeqCst) {
+            return Err(Status::aborted("leadership transfer already in progress"));
+        }
+        println!("transferring leadership to node {}", target);
+
+        let result = async {
+            if !self.wait_for_in_flight(TRANSFER_TIMEOUT).await {
+                return Err(Status::deadline_exceeded("in-flight writes did not drain"));
+            }
+            self.wait_for_catch_up(target, addr, TRANSFER_TIMEOUT)
+                .await?;
+            match self.request_leadership(target, addr).await? {
+                true => Ok(()),
+                false => Err(Status::aborted(format!(
+                    "node {} could not win phase 1",
+                    target
+                ))),
+            }
+        }
+        .await;
+
+        self.transferring.store(false, Ordering::SeqCst);
+        result
+    }
+
+    async fn wait_for_catch_up(
+        &self,
+        target: i32,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Result<(), Status> {
+        let applied_slot = self.proposer.lock().await.get_last_applied_slot();
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let mut client = self.connections.get_probe_client(target, addr).await?;
+            let target_slot = client
+                .ping(PingRequest {})
+                .await?
+                .into_inner()
+                .last_applied_slot;
+            if target_slot >= applied_slot {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(Status::deadline_exceeded(format!(
+                    "node {} applied {} of {} slots",
+                    target, target_slot, applied_slot
+                )));
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// If this node leads, asks the most caught-up active peer to take over.
+    pub async fn hand_off_leadership(&self) {
+        if *self.leader_id.lock().await != Some(self.node_id) {
+            return;
+        }
+        let applied_slot = self.proposer.lock().await.get_last_applied_slot();
+        let candidates =
+            handoff_candidates(&*self.clusters.lock().await, self.node_id, applied_slot);
+
+        for (peer_id, addr) in candidates {
+            match self.request_leadership(peer_id, addr).await {
+                Ok(true) => {
+                    println!("leadership handed off to node {}", peer_id);
+                    return;
+                }
+                Ok(false) => println!("node {} could not take leadership", peer_id),
+                Err(e) => println!("leadership hand off to node {} failed {}", peer_id, e),
+            }
+        }
+        eprintln!("no caught-up peer took leadership");
+    }
+
+    async fn request_leadership(&self, peer_id: i32, addr: SocketAddr) -> Result<bool, Status> {
+        let mut client = self.connections.get_client(peer_id, addr).await?;
+        let request = TakeLeadershipRequest {
+            from_node_id: self.node_id,
+        };
+        let reply = client.take_leadership(request).await?.into_inner();
+
+        Ok(reply.success && reply.leader_id == Some(peer_id))
+    }
+}
+
+/// Active voters other than `node_id` that applied at least `applied_slot`,
+/// most caught up first, then fastest. Peers without a measured round trip
+/// go after the measured ones.
+fn handoff_candidates(
+    clusters: &Membership,
+    node_id: i32,
+    applied_slot: i64,
+) -> Vec<(i32, SocketAddr)> {
+    let mut candidates: Vec<_> = clusters
+        .values()
+        .filter(|node| node.node_id != node_id)
+        .filter(|node| node.status == ConnectionStatus::Active && node.is_voter())
+        .filter(|node| node.applied_slot >= applied_slot)
+        .collect();
+    candidates.sort_by_key(|node| (-node.applied_slot, node.rtt.is_none(), node.rtt));
+    candidates
+        .into_iter()
+        .map(|node| (node.node_id, node.addr))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paxos::{NodeConfig, NodeRole};
+
+    fn peer(node_id: i32, applied_slot: i64, rtt_ms: Option<u64>) -> NodeConfig {
+        let addr = SocketAddr::from(([10, 0, 0, node_id as u8], 9000));
+        let mut node = NodeConfig::new(node_id, addr, ConnectionStatus::Retrying);
+        match rtt_ms {
+            Some(rtt_ms) => node.record_success(Duration::from_millis(rtt_ms), applied_slot),
+            None => {
+                node.record_success(Duration::ZERO, applied_slot);
+                node.rtt = None;
+            }
+        }
+        node
+    }
+
+    fn order(peers: Vec<NodeConfig>, applied_slot: i64) -> Vec<i32> {
+        let clusters: Membership = peers.into_iter().map(|node| (node.node_id, node)).collect();
+        handoff_candidates(&clusters, 1, applied_slot)
+            .into_iter()
+            .map(|(node_id, _)| node_id)
+            .collect()
+    }
+
+    #[test]
+    fn most_caught_up_then_fastest_first() {
+        let peers = vec![
+            peer(1, 50, Some(1)),
+            peer(2, 50, Some(30)),
+            peer(3, 50, Some(5)),
+            peer(4, 60, Some(90)),
+        ];
+        assert_eq!(order(peers, 50), vec![4, 3, 2]);
+    }
+
+    #[test]
+    fn unmeasured_peers_go_last() {
+        let peers = vec![
+            peer(2, 50, None),
+            peer(3, 50, Some(40)),
+            peer(4, 50, Some(2)),
+        ];
+        assert_eq!(order(peers, 50), vec![4, 3, 2]);
+    }
+
+    #[test]
+    fn lagging_unhealthy_and_learner_peers_are_skipped() {
+        let mut learner = peer(3, 50, Some(1));
+        learner.set_role(NodeRole::Learner);
+        let mut unreachable = peer(4, 50, Some(1));
+        unreachable.record_failure(1);
+        let peers = vec![
+            peer(2, 49, Some(1)),
+            learner,
+            unreachable,
+            peer(5, 50, Some(9)),
+        ];
+        assert_eq!(order(peers, 50), vec![5]);
+    }
+
+    #[tokio::test]
+    async fn transfer_needs_a_registered_voter_and_this_node_leading() {
+        let service = PaxosService::for_tests();
+        {
+            let mut clusters = service.clusters.lock().await;
+            clusters.insert(2, peer(2, 0, Some(1)));
+            let mut learner = peer(3, 0, Some(1));
+            learner.set_role(NodeRole::Learner);
+            clusters.insert(3, learner);
+        }
+
+        assert!(service.transfer_leadership_to(1).await.is_ok());
+        for target in [3, 7] {
+            let status = service.transfer_leadership_to(target).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound, "node {}", target);
+        }
+
+        service.transferring.store(true, Ordering::SeqCst);
+        let status = service.transfer_leadership_to(2).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        // the transfer in progress is not cancelled by the refused one
+        assert!(service.transferring.load(Ordering::SeqCst));
+
+        *service.leader_id.lock().await = Some(2);
+        let status = service.transfer_leadership_to(2).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,918 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::{Request, Status};
+
+use super::{ConnectionStatus, Membership, NodeConfig, NodeRole, PaxosService};
+use crate::auth::{self, Principal};
+use crate::connection::PeerClient;
+use crate::discovery::Peer;
+use crate::proposal::Proposal;
+use crate::proto::{
+    self, Member, PingRequest, ProtoConfigChangeKind, ProtoNodeRole, RegisterRequest,
+};
+use crate::tls;
+
+/// Membership command replicated through the log, so every node applies the
+/// same change at the same slot and agrees on the quorum size.
+///
+/// Changes are applied one at a time and each adds or removes at most one
+/// voter: a new change is only proposed once the previous one was chosen,
+/// which keeps any two consecutive majorities overlapping. Replacing a lost
+/// voter is therefore an `AddVoter` followed by a `RemoveVoter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigChange {
+    /// Adds a voter, or moves an existing voter to a new address.
+    AddVoter {
+        node_id: i32,
+        addr: SocketAddr,
+    },
+    RemoveVoter {
+        node_id: i32,
+    },
+    /// Adds a node that receives commits without counting toward quorum.
+    AddLearner {
+        node_id: i32,
+        addr: SocketAddr,
+    },
+    PromoteLearner {
+        node_id: i32,
+    },
+}
+
+impl ConfigChange {
+    /// True when applying the change would leave `clusters` as it is, which
+    /// makes repeated registrations free.
+    pub fn is_noop(&self, clusters: &Membership) -> bool {
+        let find = |node_id: i32| clusters.get(&node_id);
+        match self {
+            ConfigChange::AddVoter { node_id, addr } => {
+                find(*node_id).is_some_and(|node| node.addr == *addr && node.is_voter())
+            }
+            ConfigChange::AddLearner { node_id, addr } => {
+                find(*node_id).is_some_and(|node| node.addr == *addr && !node.is_voter())
+            }
+            ConfigChange::PromoteLearner { node_id } => {
+                find(*node_id).is_some_and(|node| node.is_voter())
+            }
+            ConfigChange::RemoveVoter { node_id } => find(*node_id).is_none(),
+        }
+    }
+
+    /// Node that leaves the cluster when the change is applied.
+    pub fn get_removed_node_id(&self) -> Option<i32> {
+        match self {
+            ConfigChange::RemoveVoter { node_id } => Some(*node_id),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, clusters: &mut Membership) {
+        match self {
+            ConfigChange::AddVoter { node_id, addr } => {
+                upsert(clusters, *node_id, *addr, NodeRole::Voter)
+            }
+            ConfigChange::AddLearner { node_id, addr } => {
+                upsert(clusters, *node_id, *addr, NodeRole::Learner)
+            }
+            ConfigChange::PromoteLearner { node_id } => {
+                if let Some(node) = clusters.get_mut(node_id) {
+                    node.set_role(NodeRole::Voter);
+                }
+            }
+            ConfigChange::RemoveVoter { node_id } => {
+                clusters.remove(node_id);
+            }
+        }
+        println!("membership change {:?} applied", self);
+    }
+
+    pub fn to_proto(&self) -> proto::ConfigChange {
+        match self {
+            ConfigChange::AddVoter { node_id, addr } => proto::ConfigChange {
+                kind: ProtoConfigChangeKind::AddVoter as i32,
+                node_id: *node_id,
+                addr: addr.to_string(),
+            },
+            ConfigChange::RemoveVoter { node_id } => proto::ConfigChange {
+                kind: ProtoConfigChangeKind::RemoveVoter as i32,
+                node_id: *node_id,
+                addr: String::new(),
+            },
+            ConfigChange::AddLearner { node_id, addr } => proto::ConfigChange {
+                kind: ProtoConfigChangeKind::AddLearner as i32,
+                node_id: *node_id,
+                addr: addr.to_string(),
+            },
+            ConfigChange::PromoteLearner { node_id } => proto::ConfigChange {
+                kind: ProtoConfigChangeKind::PromoteLearner as i32,
+                node_id: *node_id,
+                addr: String::new(),
+            },
+        }
+    }
+
+    pub fn from_proto(change: &proto::ConfigChange) -> Result<Self, Status> {
+        let addr = || {
+            change.addr.parse::<SocketAddr>().map_err(|e| {
+                Status::invalid_argument(format!("invalid address {}: {}", change.addr, e))
+            })
+        };
+        match ProtoConfigChangeKind::try_from(change.kind) {
+            Ok(ProtoConfigChangeKind::AddVoter) => Ok(ConfigChange::AddVoter {
+                node_id: change.node_id,
+                addr: addr()?,
+            }),
+            Ok(ProtoConfigChangeKind::RemoveVoter) => Ok(ConfigChange::RemoveVoter {
+                node_id: change.node_id,
+            }),
+            Ok(ProtoConfigChangeKind::AddLearner) => Ok(ConfigChange::AddLearner {
+                node_id: change.node_id,
+                addr: addr()?,
+            }),
+            Ok(ProtoConfigChangeKind::PromoteLearner) => Ok(ConfigChange::PromoteLearner {
+                node_id: change.node_id,
+            }),
+            Err(_) => Err(Status::invalid_argument(format!(
+                "unknown membership change {}",
+                change.kind
+            ))),
+        }
+    }
+}
+
+/// Adds the node or, when the id is already known, moves it to `addr`.
+fn upsert(clusters: &mut Membership, node_id: i32, addr: SocketAddr, role: NodeRole) {
+    let node = clusters
+        .entry(node_id)
+        .and_modify(|node| node.set_addr(addr))
+        .or_insert_with(|| NodeConfig::new(node_id, addr, ConnectionStatus::Active));
+    node.set_role(role);
+}
+
+impl PaxosService {
+    /// Proposes `change` through consensus. Only the leader may change
+    /// membership and only one change may be in flight at a time.
+    pub(super) async fn change_membership(&self, change: ConfigChange) -> Result<bool, Status> {
+        if *self.leader_id.lock().await != Some(self.node_id) {
+            return Err(Status::failed_precondition(format!(
+                "node {} is not the leader",
+                self.node_id
+            )));
+        }
+        if change.is_noop(&*self.clusters.lock().await) {
+            println!("membership change {:?} already applied", change);
+            return Ok(true);
+        }
+        if self.config_pending.swap(true, Ordering::SeqCst) {
+            return Err(Status::aborted("another membership change is in progress"));
+        }
+
+        let proposal = Proposal::new(0, vec![]).with_config(Some(change));
+        let chosen = self.replicate(proposal).await;
+
+        self.config_pending.store(false, Ordering::SeqCst);
+        chosen.map(|result| result.is_some())
+    }
+
+    /// Learns a chosen proposal locally, applying membership commands to
+    /// `clusters` alongside the state machine, and returns the result,
+    /// `None` when it was not applied now, see `Learner::insert`.
+    pub(super) async fn apply_commit(&self, proposal: Proposal) -> Option<Vec<u8>> {
+        let (change, result) = {
+            let mut proposer = self.proposer.lock().await;
+            let change = proposal
+                .get_config()
+                .filter(|_| !proposer.is_applied(proposal.get_slot()))
+                .cloned();
+            (change, proposer.commit(proposal))
+        };
+        if let Some(change) = &change {
+            change.apply(&mut *self.clusters.lock().await);
+            if let Some(removed) = change.get_removed_node_id() {
+                self.connections.remove(removed).await;
+                if removed == self.node_id {
+                    println!("this node was removed from the cluster");
+                }
+            }
+            // a promoted learner starts taking writes and votes
+            if *change
+                == (ConfigChange::PromoteLearner {
+                    node_id: self.node_id,
+                })
+            {
+                *self.role.lock().await = NodeRole::Voter;
+            }
+        }
+        result
+    }
+
+    /// With auth, peer RPCs must carry the cluster token. With TLS, they
+    /// must also be made with a node certificate. A request
+    /// speaking for node `claimed` must come from that node, other peer
+    /// requests from a member.
+    pub(super) async fn authorize_peer<T>(
+        &self,
+        request: &Request<T>,
+        claimed: Option<i32>,
+    ) -> Result<(), Status> {
+        if self.auth_enabled && !auth::principal(request).is_some_and(Principal::is_member) {
+            return Err(Status::permission_denied(
+                "peer requests are restricted to cluster members",
+            ));
+        }
+        if !self.verify_peers {
+            return Ok(());
+        }
+        self.authorize_peer_cert(tls::peer_node_id(request), claimed)
+            .await
+    }
+
+    /// Certificate half of `authorize_peer`, given the node id of the
+    /// client certificate.
+    async fn authorize_peer_cert(
+        &self,
+        cert_node_id: Option<i32>,
+        claimed: Option<i32>,
+    ) -> Result<(), Status> {
+        let node_id = cert_node_id
+            .ok_or_else(|| Status::unauthenticated("peer requests need a node certificate"))?;
+        match claimed {
+            Some(claimed) if claimed == node_id => Ok(()),
+            Some(claimed) => Err(Status::permission_denied(format!(
+                "certificate of node {} cannot act as node {}",
+                node_id, claimed
+            ))),
+            None if self.clusters.lock().await.contains_key(&node_id) => Ok(()),
+            None => Err(Status::permission_denied(format!(
+                "node {} is not a member",
+                node_id
+            ))),
+        }
+    }
+
+    /// Client for the leader when this node is a follower, `None` on the
+    /// leader itself. Membership requests are forwarded through it.
+    pub(super) async fn leader_client(&self) -> Result<Option<PeerClient>, Status> {
+        let leader_id = self
+            .leader_id
+            .lock()
+            .await
+            .ok_or_else(|| Status::unavailable("leader is not known"))?;
+        if leader_id == self.node_id {
+            return Ok(None);
+        }
+        let leader_addr = self
+            .clusters
+            .lock()
+            .await
+            .get(&leader_id)
+            .map(|node| node.addr)
+            .ok_or_else(|| Status::not_found(format!("leader {} is not registered", leader_id)))?;
+        let client = self.connections.get_client(leader_id, leader_addr).await?;
+        Ok(Some(client))
+    }
+
+    /// Takes a voter or learner out of the cluster. The leader cannot remove
+    /// itself, leadership has to be transferred first.
+    pub(super) async fn remove_node_by_id(&self, node_id: i32) -> Result<bool, Status> {
+        if node_id == self.node_id {
+            return Err(Status::failed_precondition(
+                "the leader cannot remove itself, transfer leadership first",
+            ));
+        }
+        if !self.clusters.lock().await.contains_key(&node_id) {
+            return Err(Status::not_found(format!(
+                "node {} is not registered",
+                node_id
+            )));
+        }
+
+        self.change_membership(ConfigChange::RemoveVoter { node_id })
+            .await
+    }
+
+    /// Proposes removal of one voter that has been `Unreachable` for longer
+    /// than `evict_after`, unless that would leave fewer than
+    /// `min_cluster_size` voters.
+    pub(super) async fn evict_unreachable(&self, evict_after: Duration, min_cluster_size: usize) {
+        if *self.leader_id.lock().await != Some(self.node_id) {
+            return;
+        }
+
+        let lost = eviction_candidate(
+            &*self.clusters.lock().await,
+            self.node_id,
+            evict_after,
+            min_cluster_size,
+        );
+        let node_id = match lost {
+            Some(node_id) => node_id,
+            None => return,
+        };
+
+        println!(
+            "evicting node {}, unreachable for over {:?}",
+            node_id, evict_after
+        );
+        match self
+            .change_membership(ConfigChange::RemoveVoter { node_id })
+            .await
+        {
+            Ok(true) => println!("node {} evicted", node_id),
+            Ok(false) => println!("node {} could not be evicted", node_id),
+            Err(e) => println!("node {} could not be evicted {}", node_id, e),
+        }
+    }
+
+    /// Validates a registration before it is proposed and returns the parsed
+    /// address. A known node id arriving from a new address is the node
+    /// moving, e.g. a rescheduled pod, and is accepted once the old address
+    /// stopped answering. An id still live elsewhere, or an address owned by
+    /// another node, is a conflict. A voter cannot come back as a learner,
+    /// that would shrink the quorum behind the operator's back.
+    pub(super) async fn check_register(&self, req: &RegisterRequest) -> Result<SocketAddr, Status> {
+        let addr: SocketAddr = req.addr.parse().map_err(|e| {
+            Status::invalid_argument(format!("invalid address {}: {}", req.addr, e))
+        })?;
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return Err(Status::invalid_argument(format!(
+                "address {} is not reachable by peers",
+                addr
+            )));
+        }
+
+        if req.role() == ProtoNodeRole::Learner {
+            let is_voter = if req.node_id == self.node_id {
+                *self.role.lock().await == NodeRole::Voter
+            } else {
+                let clusters = self.clusters.lock().await;
+                clusters.get(&req.node_id).is_some_and(NodeConfig::is_voter)
+            };
+            if is_voter {
+                return Err(Status::failed_precondition(format!(
+                    "node {} is a voter and cannot join as a learner, remove it first",
+                    req.node_id
+                )));
+            }
+        }
+
+        let live_at_other_addr = match self.clusters.lock().await.get(&req.node_id) {
+            _ if req.node_id == self.node_id => (addr != self.addr).then_some(self.addr),
+            Some(node) if node.addr != addr && node.status == ConnectionStatus::Active => {
+                Some(node.addr)
+            }
+            _ => None,
+        };
+        if let Some(current) = live_at_other_addr {
+            return Err(Status::already_exists(format!(
+                "node {} is still live at {}",
+                req.node_id, current
+            )));
+        }
+
+        let owner = if addr == self.addr {
+            Some(self.node_id)
+        } else {
+            self.clusters
+                .lock()
+                .await
+                .values()
+                .find(|node| node.addr == addr)
+                .map(|node| node.node_id)
+        };
+        match owner {
+            Some(owner) if owner != req.node_id && Some(owner) != req.replaces_node_id => Err(
+                Status::already_exists(format!("address {} belongs to node {}", addr, owner)),
+            ),
+            _ => Ok(addr),
+        }
+    }
+
+    /// Current membership, including this node, as sent to joining nodes.
+    pub(super) async fn members(&self) -> Vec<Member> {
+        let clusters = self.clusters.lock().await;
+        let mut members: Vec<Member> = clusters
+            .values()
+            .map(|node| Member {
+                node_id: node.node_id,
+                addr: node.addr.to_string(),
+                role: ProtoNodeRole::from(node.role) as i32,
+            })
+            .collect();
+        if !clusters.contains_key(&self.node_id) {
+            members.push(Member {
+                node_id: self.node_id,
+                addr: self.addr.to_string(),
+                role: ProtoNodeRole::from(*self.role.lock().await) as i32,
+            });
+        }
+        members
+    }
+
+    /// Turns a learner into a voter once it applied everything this leader
+    /// applied, so it never joins a quorum while missing chosen values.
+    pub(super) async fn promote_learner(&self, node_id: i32) -> Result<bool, Status> {
+        let (addr, role) = self
+            .clusters
+            .lock()
+            .await
+            .get(&node_id)
+            .map(|node| (node.addr, node.role))
+            .ok_or_else(|| Status::not_found(format!("node {} is not registered", node_id)))?;
+        if role == NodeRole::Voter {
+            return Ok(true);
+        }
+
+        let applied_slot = self.proposer.lock().await.get_last_applied_slot();
+        let mut client = self.connections.get_client(node_id, addr).await?;
+        let learner_slot = client
+            .ping(PingRequest {})
+            .await?
+            .into_inner()
+            .last_applied_slot;
+        if learner_slot < applied_slot {
+            return Err(Status::failed_precondition(format!(
+                "learner {} applied {} of {} slots",
+                node_id, learner_slot, applied_slot
+            )));
+        }
+
+        self.change_membership(ConfigChange::PromoteLearner { node_id })
+            .await
+    }
+
+    /// Adds discovered peers this node does not know yet as learners, checked
+    /// like a registration. They become voters through `promote_learner`
+    /// once caught up. Followers leave membership to the leader.
+    pub async fn reconcile_discovered(&self, peers: &[Peer]) {
+        if *self.leader_id.lock().await != Some(self.node_id) {
+            return;
+        }
+        for peer in peers {
+            if peer.node_id == self.node_id
+                || self.clusters.lock().await.contains_key(&peer.node_id)
+            {
+                continue;
+            }
+            let request = RegisterRequest {
+                node_id: peer.node_id,
+                addr: peer.addr.to_string(),
+                role: ProtoNodeRole::Learner as i32,
+                ..Default::default()
+            };
+            let addr = match self.check_register(&request).await {
+                Ok(addr) => addr,
+                Err(e) => {
+                    println!("discovered node {} rejected {}", peer.node_id, e.message());
+                    continue;
+                }
+            };
+            let change = ConfigChange::AddLearner {
+                node_id: peer.node_id,
+                addr,
+            };
+            match self.change_membership(change).await {
+                Ok(true) => println!("node {} joined as a learner at {}", peer.node_id, addr),
+                Ok(false) => println!("node {} could not be added", peer.node_id),
+                Err(e) => println!("node {} could not be added {}", peer.node_id, e),
+            }
+        }
+    }
+}
+
+/// How often the leader looks for voters to evict.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Voter other than `node_id` that has been `Unreachable` for at least
+/// `evict_after`, unless the cluster is already down to `min_cluster_size`
+/// voters counting `node_id`.
+fn eviction_candidate(
+    clusters: &Membership,
+    node_id: i32,
+    evict_after: Duration,
+    min_cluster_size: usize,
+) -> Option<i32> {
+    let others = || {
+        clusters
+            .values()
+            .filter(move |node| node.is_voter() && node.node_id != node_id)
+    };
+    let voters = others().count() + 1;
+    let lost = others()
+        .find(|node| {
+            node.unreachable_since
+                .is_some_and(|since| since.elapsed() >= evict_after)
+        })?
+        .node_id;
+    if voters <= min_cluster_size {
+        println!(
+            "node {} is lost but the cluster is at its minimum size {}",
+            lost, min_cluster_size
+        );
+        return None;
+    }
+    Some(lost)
+}
+
+/// Periodically evicts voters lost for longer than `evict_after`, see
+/// `PaxosService::evict_unreachable`.
+pub fn spawn_evictor(paxos: Arc<PaxosService>, evict_after: Duration, min_cluster_size: usize) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            ticker.tick().await;
+            paxos.evict_unreachable(evict_after, min_cluster_size).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn voters(ids: &[i32]) -> Membership {
+        let mut clusters = Membership::new();
+        for id in ids {
+            ConfigChange::AddVoter {
+                node_id: *id,
+                addr: addr(9000 + *id as u16),
+            }
+            .apply(&mut clusters);
+        }
+        clusters
+    }
+
+    #[test]
+    fn add_voter_is_noop_only_at_the_same_address() {
+        let clusters = voters(&[1, 2]);
+        let same = ConfigChange::AddVoter {
+            node_id: 2,
+            addr: addr(9002),
+        };
+        let moved = ConfigChange::AddVoter {
+            node_id: 2,
+            addr: addr(9102),
+        };
+        let new = ConfigChange::AddVoter {
+            node_id: 3,
+            addr: addr(9003),
+        };
+        assert!(same.is_noop(&clusters));
+        assert!(!moved.is_noop(&clusters));
+        assert!(!new.is_noop(&clusters));
+    }
+
+    #[test]
+    fn add_voter_moves_a_known_node() {
+        let mut clusters = voters(&[1, 2]);
+        ConfigChange::AddVoter {
+            node_id: 2,
+            addr: addr(9102),
+        }
+        .apply(&mut clusters);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[&2].addr, addr(9102));
+    }
+
+    #[test]
+    fn remove_voter_is_noop_once_removed() {
+        let mut clusters = voters(&[1, 2, 3]);
+        let change = ConfigChange::RemoveVoter { node_id: 3 };
+        assert!(!change.is_noop(&clusters));
+        assert_eq!(change.get_removed_node_id(), Some(3));
+        change.apply(&mut clusters);
+        assert!(!clusters.contains_key(&3));
+        assert!(change.is_noop(&clusters));
+    }
+
+    #[test]
+    fn learner_is_promoted_to_voter() {
+        let mut clusters = voters(&[1]);
+        ConfigChange::AddLearner {
+            node_id: 2,
+            addr: addr(9002),
+        }
+        .apply(&mut clusters);
+        assert!(!clusters[&2].is_voter());
+        let promote = ConfigChange::PromoteLearner { node_id: 2 };
+        assert!(!promote.is_noop(&clusters));
+        promote.apply(&mut clusters);
+        assert!(clusters[&2].is_voter());
+        assert!(promote.is_noop(&clusters));
+    }
+
+    #[test]
+    fn replacement_keeps_majorities_overlapping() {
+        // {1,2,3} loses 3, 4 replaces it: each step changes one voter, so a
+        // majority of the old set always shares a node with the new one
+        let mut clusters = voters(&[1, 2, 3]);
+        let steps = [
+            ConfigChange::AddVoter {
+                node_id: 4,
+                addr: addr(9004),
+            },
+            ConfigChange::RemoveVoter { node_id: 3 },
+        ];
+        for step in steps {
+            let before: Vec<i32> = clusters.keys().copied().collect();
+            step.apply(&mut clusters);
+            let after: Vec<i32> = clusters.keys().copied().collect();
+            let changed = before.iter().filter(|id| !after.contains(id)).count()
+                + after.iter().filter(|id| !before.contains(id)).count();
+            assert_eq!(changed, 1);
+        }
+        assert_eq!(clusters.keys().copied().collect::<Vec<_>>(), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn changes_survive_the_proto_round_trip() {
+        let changes = [
+            ConfigChange::AddVoter {
+                node_id: 1,
+                addr: addr(9001),
+            },
+            ConfigChange::RemoveVoter { node_id: 2 },
+            ConfigChange::AddLearner {
+                node_id: 3,
+                addr: addr(9003),
+            },
+            ConfigChange::PromoteLearner { node_id: 3 },
+        ];
+        for change in changes {
+            assert_eq!(
+                ConfigChange::from_proto(&change.to_proto()).unwrap(),
+                change
+            );
+        }
+    }
+
+    fn with_principal(principal: Option<Principal>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(principal) = principal {
+            request.extensions_mut().insert(principal);
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn peer_requests_need_a_member_with_auth_on() {
+        let open = PaxosService::for_tests();
+        assert!(open
+            .authorize_peer(&with_principal(None), None)
+            .await
+            .is_ok());
+
+        let service = PaxosService::for_tests().with_auth(true);
+        let member = with_principal(Some(Principal::Member));
+        assert!(service.authorize_peer(&member, Some(2)).await.is_ok());
+
+        let client = Principal::Client {
+            subject: String::from("billing"),
+            roles: vec![],
+        };
+        for request in [with_principal(None), with_principal(Some(client))] {
+            let status = service.authorize_peer(&request, None).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        }
+    }
+
+    #[tokio::test]
+    async fn certificate_must_match_the_claimed_node() {
+        let service = PaxosService::for_tests().with_peer_verification(true);
+        assert!(service.authorize_peer_cert(Some(2), Some(2)).await.is_ok());
+        let status = service
+            .authorize_peer_cert(Some(3), Some(2))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let status = service
+            .authorize_peer_cert(None, Some(2))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // without a claim the certificate has to belong to a member
+        assert!(service.authorize_peer_cert(Some(1), None).await.is_ok());
+        let status = service
+            .authorize_peer_cert(Some(5), None)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn add_learner_is_noop_only_for_a_learner() {
+        let mut clusters = voters(&[1, 2]);
+        let learner = ConfigChange::AddLearner {
+            node_id: 2,
+            addr: addr(9002),
+        };
+        assert!(!learner.is_noop(&clusters));
+
+        ConfigChange::RemoveVoter { node_id: 2 }.apply(&mut clusters);
+        learner.apply(&mut clusters);
+        assert!(learner.is_noop(&clusters));
+    }
+
+    #[tokio::test]
+    async fn voter_cannot_register_as_a_learner() {
+        let service = PaxosService::for_tests();
+        {
+            let mut clusters = service.clusters.lock().await;
+            clusters.insert(2, NodeConfig::new(2, addr(9002), ConnectionStatus::Active));
+            let mut learner = NodeConfig::new(3, addr(9003), ConnectionStatus::Active);
+            learner.set_role(NodeRole::Learner);
+            clusters.insert(3, learner);
+        }
+        let as_learner = |node_id: i32, addr: SocketAddr| RegisterRequest {
+            node_id,
+            addr: addr.to_string(),
+            role: ProtoNodeRole::Learner as i32,
+            ..Default::default()
+        };
+
+        for (node_id, addr) in [(1, service.addr), (2, addr(9002))] {
+            let status = service
+                .check_register(&as_learner(node_id, addr))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        }
+        assert!(service
+            .check_register(&as_learner(3, addr(9003)))
+            .await
+            .is_ok());
+    }
+
+    fn register(node_id: i32, addr: &str) -> RegisterRequest {
+        RegisterRequest {
+            node_id,
+            addr: addr.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn discovered_peers_join_as_checked_learners() {
+        let service = PaxosService::for_tests();
+        let known = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut learner = NodeConfig::new(2, known, ConnectionStatus::Active);
+        learner.set_role(NodeRole::Learner);
+        service.clusters.lock().await.insert(2, learner);
+
+        let peer = |node_id, addr| Peer { node_id, addr };
+        service
+            .reconcile_discovered(&[
+                peer(2, known),
+                peer(3, SocketAddr::from(([127, 0, 0, 1], 2))),
+                peer(4, SocketAddr::from(([0, 0, 0, 0], 9004))),
+                peer(5, known),
+            ])
+            .await;
+
+        let clusters = service.clusters.lock().await;
+        let roles: Vec<(i32, NodeRole)> = clusters
+            .values()
+            .map(|node| (node.node_id, node.role))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                (1, NodeRole::Voter),
+                (2, NodeRole::Learner),
+                (3, NodeRole::Learner)
+            ],
+            "known node untouched, unreachable and taken addresses rejected"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_addresses_are_rejected() {
+        let service = PaxosService::for_tests();
+        for addr in ["not an address", "0.0.0.0:9002", "10.0.0.1:0", "[::]:9002"] {
+            let status = service
+                .check_register(&register(2, addr))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", addr);
+        }
+        assert_eq!(
+            service
+                .check_register(&register(2, "10.0.0.1:9002"))
+                .await
+                .unwrap(),
+            addr(9002)
+        );
+    }
+
+    #[tokio::test]
+    async fn live_node_cannot_move() {
+        let service = PaxosService::for_tests();
+        let lost = NodeConfig::new(3, addr(9003), ConnectionStatus::Unreachable);
+        {
+            let mut clusters = service.clusters.lock().await;
+            clusters.insert(2, NodeConfig::new(2, addr(9002), ConnectionStatus::Active));
+            clusters.insert(3, lost);
+        }
+
+        let moved = addr(9102).to_string();
+        let status = service
+            .check_register(&register(2, &moved))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        let status = service
+            .check_register(&register(1, &moved))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        // the old address stopped answering, the node was rescheduled
+        let moved = addr(9103).to_string();
+        assert!(service.check_register(&register(3, &moved)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn address_belongs_to_one_node() {
+        let service = PaxosService::for_tests();
+        let lost = NodeConfig::new(2, addr(9002), ConnectionStatus::Unreachable);
+        service.clusters.lock().await.insert(2, lost);
+
+        let taken = addr(9002).to_string();
+        let status = service
+            .check_register(&register(4, &taken))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+        let status = service
+            .check_register(&register(4, &service.addr.to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+        // a replacement takes over the address of the node it replaces
+        let mut replacement = register(4, &taken);
+        replacement.replaces_node_id = Some(2);
+        assert!(service.check_register(&replacement).await.is_ok());
+    }
+
+    fn cluster(unreachable: &[i32], voters: i32) -> Membership {
+        let mut clusters = Membership::new();
+        for node_id in 1..=voters {
+            let mut node = NodeConfig::new(
+                node_id,
+                addr(9000 + node_id as u16),
+                ConnectionStatus::Active,
+            );
+            if unreachable.contains(&node_id) {
+                node.record_failure(1);
+            }
+            clusters.insert(node_id, node);
+        }
+        clusters
+    }
+
+    #[test]
+    fn eviction_keeps_the_minimum_cluster_size() {
+        let lost = |clusters: &Membership, min_cluster_size: usize| {
+            eviction_candidate(clusters, 1, Duration::ZERO, min_cluster_size)
+        };
+        let five = cluster(&[4], 5);
+        assert_eq!(lost(&five, 3), Some(4));
+        assert_eq!(lost(&five, 4), Some(4));
+        assert_eq!(lost(&five, 5), None);
+
+        let three = cluster(&[3], 3);
+        assert_eq!(lost(&three, 3), None);
+        assert_eq!(lost(&three, 2), Some(3));
+    }
+
+    #[test]
+    fn only_voters_lost_long_enough_are_evicted() {
+        let clusters = cluster(&[4], 5);
+        let evict_after = Duration::from_secs(3600);
+        assert_eq!(eviction_candidate(&clusters, 1, evict_after, 3), None);
+        assert_eq!(
+            eviction_candidate(&cluster(&[], 5), 1, Duration::ZERO, 3),
+            None
+        );
+
+        // learners do not count towards the size and are never evicted here
+        let mut clusters = cluster(&[4, 5], 5);
+        clusters.get_mut(&4).unwrap().set_role(NodeRole::Learner);
+        clusters.get_mut(&5).unwrap().set_role(NodeRole::Learner);
+        assert_eq!(eviction_candidate(&clusters, 1, Duration::ZERO, 3), None);
+
+        // this node never evicts itself
+        assert_eq!(
+            eviction_candidate(&cluster(&[1], 5), 1, Duration::ZERO, 3),
+            None
+        );
+    }
+}
@@ -1,57 +1,117 @@
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use ::tokio::sync::Mutex;
-use tonic::transport::Channel;
-
-use crate::acceptor::Promise;
-use crate::proposal;
-use crate::proto::paxos_client::PaxosClient;
-use crate::proto::{InsertRequest, InsertResponse, RegisterRequest, RegisterResponse};
-use crate::{acceptor::Acceptor, learner::Learner, proposal::Proposal, proposer::Proposer};
-use crate::{
-    proposer,
-    proto::{
-        paxos_server::{Paxos, PaxosServer},
-        AcceptorRequest, AcceptorResponse, LearnerRequest, LearnerResponse, PingRequest,
-        PingResponse, ProposerRequest, ProposerResponse,
-    },
+use tokio_stream::Stream;
+
+use crate::auth::{self, Access, Permission, Principal};
+use crate::backup;
+use crate::connection::ConnectionManager;
+use crate::discovery::Discovery;
+use crate::export;
+use crate::proposal::RequestId;
+use crate::proto::{
+    cluster_admin_server::ClusterAdmin, kv_client_server::KvClient, paxos_peer_server::PaxosPeer,
+    AcceptorRequest, AcceptorResponse, LearnerRequest, LearnerResponse, PingRequest, PingResponse,
+    ProposerRequest, ProposerResponse,
+};
+use crate::proto::{
+    BackupChunk, BackupRequest, BackupToFileRequest, BackupToFileResponse, ExecuteRequest,
+    ExecuteResponse, ExportChunk, ExportRequest, GetRequest, GetResponse, ImportChunk,
+    ImportResponse, InsertRequest, InsertResponse, LeaderRequest, LeaderResponse, PeerStatus,
+    PromoteRequest, PromoteResponse, ProtoConnectionStatus, ProtoNodeRole, QueryRequest,
+    QueryResponse, RangeHash, RegisterRequest, RegisterResponse, RemoveNodeRequest,
+    RemoveNodeResponse, StateHashRequest, StateHashResponse, StatusRequest, StatusResponse,
+    TakeLeadershipRequest, TakeLeadershipResponse, TransferLeadershipRequest,
+    TransferLeadershipResponse,
 };
-use tonic::{Request, Response, Status};
+use crate::state_machine::KvStateMachine;
+use crate::{proposal::Proposal, proposer::Proposer};
+use tonic::{Request, Response, Status, Streaming};
+
+mod access;
+mod leadership;
+mod membership;
+mod replication;
+mod resync;
+mod verifier;
+
+pub use membership::{spawn_evictor, ConfigChange};
+pub use resync::spawn_resync;
+pub use verifier::spawn_verifier;
+
+/// Known nodes keyed by node id, so a node id maps to exactly one address.
+pub type Membership = BTreeMap<i32, NodeConfig>;
 
 pub struct PaxosService {
     node_id: i32,
+    cluster_id: String,
     addr: SocketAddr,
-    clusters: Arc<Mutex<Vec<NodeConfig>>>,
-    connections: Arc<Mutex<Vec<Channel>>>,
+    role: Mutex<NodeRole>,
+    clusters: Arc<Mutex<Membership>>,
+    connections: Arc<ConnectionManager>,
     proposer: Arc<Mutex<Proposer>>,
+    // held by `replicate` from picking a slot until its value is committed
+    proposing: Mutex<()>,
     leader_id: Arc<Mutex<Option<i32>>>,
+    discovery: Arc<dyn Discovery>,
+    draining: AtomicBool,
+    transferring: AtomicBool,
+    config_pending: AtomicBool,
+    in_flight: Arc<AtomicUsize>,
+    consistency_checks: AtomicU64,
+    divergences: AtomicU64,
+    verify_peers: bool,
+    auth_enabled: bool,
+    backup_dir: Option<PathBuf>,
 }
 
 #[derive(Debug)]
 pub struct NodeConfig {
     node_id: i32,
     addr: SocketAddr,
-    status: Connection_Status,
+    status: ConnectionStatus,
+    last_seen: Option<Instant>,
+    rtt: Option<Duration>,
+    applied_slot: i64,
+    failures: u32,
+    unreachable_since: Option<Instant>,
+    role: NodeRole,
+    diverged_slot: Option<i64>,
 }
 
-#[derive(Debug)]
-enum Connection_Status {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
     Active,
     Unreachable,
     Retrying,
 }
 
+/// Learners receive every commit but never vote or lead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeRole {
+    Voter,
+    Learner,
+}
+
 impl PaxosService {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         node_id: i32,
+        cluster_id: String,
         addr: SocketAddr,
-        clusters: Arc<Mutex<Vec<NodeConfig>>>,
-        connections: Arc<Mutex<Vec<Channel>>>,
+        role: NodeRole,
+        clusters: Arc<Mutex<Membership>>,
+        connections: Arc<ConnectionManager>,
         proposer: Arc<Mutex<Proposer>>,
         leader_id: Option<i32>,
+        discovery: Arc<dyn Discovery>,
     ) -> Self {
-        let mut leader: i32 = 0;
         let id: i32 = match leader_id {
             Some(id) => id,
             None => node_id,
@@ -59,281 +119,1079 @@ impl PaxosService {
 
         Self {
             node_id,
+            cluster_id,
             addr,
+            role: Mutex::new(role),
             clusters,
             connections,
             proposer,
+            proposing: Mutex::new(()),
             leader_id: Arc::new(Mutex::new(Some(id))),
+            discovery,
+            draining: AtomicBool::new(false),
+            transferring: AtomicBool::new(false),
+            config_pending: AtomicBool::new(false),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            consistency_checks: AtomicU64::new(0),
+            divergences: AtomicU64::new(0),
+            verify_peers: false,
+            auth_enabled: false,
+            backup_dir: None,
         }
     }
+
+    /// Requires peer RPCs to come from a node certificate, see
+    /// `authorize_peer`.
+    pub fn with_peer_verification(mut self, verify_peers: bool) -> Self {
+        self.verify_peers = verify_peers;
+        self
+    }
+
+    /// Requires every request to carry a principal allowed to make it.
+    pub fn with_auth(mut self, auth_enabled: bool) -> Self {
+        self.auth_enabled = auth_enabled;
+        self
+    }
+
+    /// Directory `backup_to_file` paths are resolved in.
+    pub fn with_backup_dir(mut self, backup_dir: Option<PathBuf>) -> Self {
+        self.backup_dir = backup_dir;
+        self
+    }
+
+    pub fn get_leader_id(&self) -> Arc<Mutex<Option<i32>>> {
+        self.leader_id.clone()
+    }
+
+    /// Takes a backup and splits it into chunks, for peers re-syncing and
+    /// operators alike.
+    async fn stream_backup(&self) -> Result<BackupStream, Status> {
+        self.check_integrity().await?;
+        let backup = backup::take(
+            &mut *self.proposer.lock().await,
+            &self.cluster_id,
+            self.node_id,
+        )
+        .map_err(|e| Status::internal(format!("backup failed {}", e)))?;
+        println!("streaming backup at slot {}", backup.applied_slot);
+
+        let chunks: Vec<Result<BackupChunk, Status>> =
+            backup::chunks(&backup).into_iter().map(Ok).collect();
+
+        Ok(Box::pin(tokio_stream::iter(chunks)))
+    }
 }
 
+type BackupStream = Pin<Box<dyn Stream<Item = Result<BackupChunk, Status>> + Send>>;
+
 #[tonic::async_trait]
-impl Paxos for PaxosService {
-    async fn ping(&self, request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
-        let reply = PingResponse { health: true };
+impl PaxosPeer for PaxosService {
+    type backupStream = BackupStream;
+
+    async fn ping(&self, _request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        let reply = PingResponse {
+            health: true,
+            last_applied_slot: self.proposer.lock().await.get_last_applied_slot(),
+            node_id: self.node_id,
+        };
 
         Ok(Response::new(reply))
     }
 
-    async fn register(
+    async fn propose(
         &self,
-        request: Request<RegisterRequest>,
-    ) -> Result<Response<RegisterResponse>, Status> {
+        request: Request<ProposerRequest>,
+    ) -> Result<Response<ProposerResponse>, Status> {
         let req = request.get_ref();
 
-        let mut clusters = self.clusters.lock().await;
+        println!("incoming request {:?}", req.clone());
+        self.authorize_peer(&request, None).await?;
+        self.check_integrity().await?;
 
-        let address: SocketAddr = req.addr.clone().parse().unwrap();
+        let proposal = Proposal::from_proto(req)?;
 
-        let node = NodeConfig::new(req.node_id.clone(), address, Connection_Status::Active);
+        let mut proposer = self.proposer.lock().await;
+        Self::check_slot_open(&proposer, req.slot)?;
 
-        clusters.push(node);
-        println!(
-            "register request arrived, new cluster status is {:?}",
-            clusters
-        );
-        let reply = RegisterResponse {
-            register_status: true,
+        let promise = proposer
+            .prepare(proposal)
+            .ok_or_else(|| Status::aborted("a higher proposal was already promised"))?;
+
+        println!("{:?}", promise);
+
+        let accepted = promise.get_accepted_proposal();
+        let reply = ProposerResponse {
+            node_id: self.node_id,
+            promised_proposal_id: promise.get_promised_proposal_id(),
+            accepted_proposal_id: accepted.map(Proposal::get_proposal_id),
+            accepted_value: accepted.map(|accepted| accepted.get_command().to_vec()),
+            accepted: accepted.map(Proposal::to_proto),
         };
 
         Ok(Response::new(reply))
     }
 
-    async fn send_register(
+    async fn accept(
+        &self,
+        request: Request<AcceptorRequest>,
+    ) -> Result<Response<AcceptorResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize_peer(&request, None).await?;
+        self.check_integrity().await?;
+
+        let config = req
+            .config
+            .as_ref()
+            .map(ConfigChange::from_proto)
+            .transpose()?;
+        let proposal = Proposal::new(req.proposal_id, req.command.clone())
+            .with_slot(req.slot)
+            .with_config(config)
+            .with_request_id(req.request_id.as_ref().map(RequestId::from));
+
+        let mut proposer = self.proposer.lock().await;
+        Self::check_slot_open(&proposer, req.slot)?;
+
+        let m_proposal = ProposerRequest {
+            proposal_id: req.proposal_id,
+            command: req.command.clone(),
+            slot: req.slot,
+            config: req.config.clone(),
+            request_id: req.request_id.clone(),
+        };
+
+        let accept = proposer
+            .accept(proposal)
+            .ok_or_else(|| Status::aborted("a higher proposal was already promised"))?;
+
+        let reply = AcceptorResponse {
+            node_id: self.node_id,
+            status: 1,
+            proposal_id: accept.proposal_id,
+            proposal: Some(m_proposal),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn commit(
+        &self,
+        request: Request<LearnerRequest>,
+    ) -> Result<Response<LearnerResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize_peer(&request, None).await?;
+
+        let config = req
+            .config
+            .as_ref()
+            .map(ConfigChange::from_proto)
+            .transpose()?;
+        let proposal = Proposal::new(req.proposal_id, req.command.clone())
+            .with_slot(req.slot)
+            .with_config(config)
+            .with_request_id(req.request_id.as_ref().map(RequestId::from));
+
+        self.apply_commit(proposal).await;
+        let reply = LearnerResponse {
+            node_id: self.node_id,
+            status: true,
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn register(
         &self,
         request: Request<RegisterRequest>,
     ) -> Result<Response<RegisterResponse>, Status> {
-        let clusters = self.clusters.lock().await;
-        let leader_id = self.leader_id.lock().await;
-        let leader_node = clusters
-            .iter()
-            .find(|node| node.node_id == leader_id.unwrap())
-            .unwrap();
-
-        let leader_addr = format!("http://{}", leader_node.addr.clone());
-        println!("leader addr is {}", leader_addr);
-        let channel = Channel::from_shared(leader_addr.to_string())
-            .unwrap()
-            .connect()
+        let req = request.get_ref();
+        // a node registers itself, followers forward registrations
+        if self
+            .authorize_peer(&request, Some(req.node_id))
             .await
-            .unwrap();
+            .is_err()
+        {
+            self.authorize_peer(&request, None).await?;
+        }
 
-        let mut client = PaxosClient::new(channel);
+        // membership is changed by the leader only, forward to it
+        if let Some(mut leader) = self.leader_client().await? {
+            return leader.register(req.clone()).await;
+        }
 
-        let request = RegisterRequest {
-            node_id: self.node_id.clone(),
-            addr: self.addr.to_string().clone(),
-            status: 0 as i32,
+        let address = self.check_register(req).await?;
+
+        let change = match req.role() {
+            ProtoNodeRole::Learner => ConfigChange::AddLearner {
+                node_id: req.node_id,
+                addr: address,
+            },
+            ProtoNodeRole::Voter => ConfigChange::AddVoter {
+                node_id: req.node_id,
+                addr: address,
+            },
         };
+        println!("register request arrived {:?}", change);
+        let mut register_status = self.change_membership(change).await?;
 
-        match client.register(request).await {
-            Ok(response) => {
-                println!("register operation successful {:?}", response);
-            }
-            Err(e) => {
-                println!("register failed successful {}", e);
+        // the lost voter only leaves once its replacement was chosen, one
+        // voter per change keeps consecutive majorities overlapping
+        match (req.replaces_node_id, req.role()) {
+            (Some(old_node_id), ProtoNodeRole::Voter)
+                if register_status && old_node_id != req.node_id =>
+            {
+                println!(
+                    "removing node {} replaced by node {}",
+                    old_node_id, req.node_id
+                );
+                register_status = self
+                    .change_membership(ConfigChange::RemoveVoter {
+                        node_id: old_node_id,
+                    })
+                    .await?;
             }
+            _ => {}
         }
 
         let reply = RegisterResponse {
-            register_status: true,
+            register_status,
+            members: self.members().await,
+            leader_id: *self.leader_id.lock().await,
         };
 
         Ok(Response::new(reply))
     }
 
-    /*
-     * send propose all peers
-     * calculate majority promise
-     * if majority check failed increase proposal id and try agian(up to 3 times)
-     * return the result
-     */
+    async fn remove_member(
+        &self,
+        request: Request<RemoveNodeRequest>,
+    ) -> Result<Response<RemoveNodeResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize_peer(&request, None).await?;
+
+        if let Some(mut leader) = self.leader_client().await? {
+            return leader.remove_member(*req).await;
+        }
+
+        let removed = self.remove_node_by_id(req.node_id).await?;
+        let reply = RemoveNodeResponse { removed };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> Result<Response<Self::backupStream>, Status> {
+        self.authorize_peer(&request, None).await?;
+        Ok(Response::new(self.stream_backup().await?))
+    }
+
+    async fn state_hash(
+        &self,
+        request: Request<StateHashRequest>,
+    ) -> Result<Response<StateHashResponse>, Status> {
+        self.authorize_peer(&request, None).await?;
+        let proposer = self.proposer.lock().await;
+
+        let reply = StateHashResponse {
+            node_id: self.node_id,
+            applied_slot: proposer.get_last_applied_slot(),
+            ranges: proposer
+                .get_range_hashes()
+                .into_iter()
+                .map(|range| RangeHash {
+                    first_slot: range.first_slot,
+                    last_slot: range.last_slot,
+                    hash: range.hash,
+                    entries: range.entries,
+                })
+                .collect(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn take_leadership(
+        &self,
+        request: Request<TakeLeadershipRequest>,
+    ) -> Result<Response<TakeLeadershipResponse>, Status> {
+        let req = request.get_ref();
+        println!("node {} asked us to take leadership", req.from_node_id);
+        self.authorize_peer(&request, Some(req.from_node_id))
+            .await?;
+        if *self.role.lock().await == NodeRole::Learner {
+            return Err(Status::failed_precondition("learner nodes cannot lead"));
+        }
+        self.check_integrity().await?;
+
+        let success = self.elect().await;
+        let reply = TakeLeadershipResponse {
+            success,
+            leader_id: *self.leader_id.lock().await,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn announce_leader(
+        &self,
+        request: Request<LeaderRequest>,
+    ) -> Result<Response<LeaderResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize_peer(&request, Some(req.node_id)).await?;
+
+        // a stale announcement must not override a newer election
+        let status = req.ballot >= self.proposer.lock().await.get_promised_ballot();
+        if status {
+            println!("node {} is the new leader", req.node_id);
+            self.set_leader(req.node_id).await;
+        }
+
+        let reply = LeaderResponse {
+            status,
+            node_id: self.node_id,
+        };
+
+        Ok(Response::new(reply))
+    }
+}
+
+#[tonic::async_trait]
+impl KvClient for PaxosService {
+    type exportStream = Pin<Box<dyn Stream<Item = Result<ExportChunk, Status>> + Send>>;
 
     async fn insert(
         &self,
         request: Request<InsertRequest>,
     ) -> Result<Response<InsertResponse>, Status> {
         let req = request.get_ref();
-        let clusters = self.clusters.clone();
-        let node_id = self.node_id.clone();
+        self.authorize(&request, Permission::Key(Access::Write, &req.key))?;
+        if KvStateMachine::is_reserved(&req.key) {
+            return Err(Status::invalid_argument(format!(
+                "key {:?} is reserved",
+                req.key
+            )));
+        }
+
+        let command = KvStateMachine::set_command(&req.key, &req.value);
+        let request_id = req.request_id.as_ref().map(RequestId::from);
+        let execution = self.execute_command(command, request_id).await?;
+
+        let reply = InsertResponse {
+            result: execution.chosen,
+            duplicate: execution.duplicate,
+        };
 
+        Ok(Response::new(reply))
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize(&request, Permission::Key(Access::Read, &req.key))?;
+        self.check_integrity().await?;
         let mut proposer = self.proposer.lock().await;
-        let old_proposer_id = proposer.get_last_seen_propose_id();
-        println!("old propose id {}", old_proposer_id);
-        proposer.set_last_seen_propose_id(old_proposer_id + 1 as i32);
-        let propose_id = proposer.get_last_seen_propose_id();
-        println!("new propose id {}", propose_id);
-        let proposal = ProposerRequest {
-            proposal_id: propose_id,
-            key: req.key.clone(),
-            value: req.value.clone(),
+
+        let result = proposer.query(&KvStateMachine::get_query(&req.key));
+        let result = KvStateMachine::decode_result(&result);
+        let reply = GetResponse {
+            value: result.value,
+            applied_slot: proposer.get_last_applied_slot(),
+            revision: result.revision,
         };
 
-        let mut accept: Vec<ProposerResponse> = vec![];
-
-        let nodes = clusters.lock().await;
-        println!("{:?}", nodes);
-        for node in nodes.iter() {
-            if node.node_id != node_id {
-                println!("node id is {}", node.node_id.clone());
-                let channel =
-                    Channel::from_shared(format!("http://{}", node.addr.clone().to_string()))
-                        .unwrap()
-                        .connect()
-                        .await
-                        .unwrap();
-
-                let mut client = PaxosClient::new(channel);
-                let response = client.propose(proposal.clone());
-                match response.await {
-                    Ok(response) => {
-                        let message = response.into_inner();
-                        println!("response {:?}", message.clone());
-                        accept.push(message.clone());
-                        println!("request is here");
-                    }
-                    Err(e) => {}
+        Ok(Response::new(reply))
+    }
+
+    async fn execute(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        // opaque commands may touch any key
+        self.authorize(&request, Permission::Key(Access::Write, ""))?;
+        let req = request.into_inner();
+
+        let request_id = req.request_id.as_ref().map(RequestId::from);
+        let reply = self.execute_command(req.command, request_id).await?;
+
+        Ok(Response::new(reply))
+    }
+
+    async fn query(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<QueryResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize(&request, Permission::Key(Access::Read, ""))?;
+        self.check_integrity().await?;
+        let mut proposer = self.proposer.lock().await;
+
+        let reply = QueryResponse {
+            result: proposer.query(&req.query),
+            applied_slot: proposer.get_last_applied_slot(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn export(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<Self::exportStream>, Status> {
+        let req = request.get_ref();
+        self.authorize(&request, Permission::Key(Access::Read, &req.prefix))?;
+        self.check_integrity().await?;
+
+        // a copy taken under the lock, lines are encoded as the client reads
+        let entries = {
+            let mut proposer = self.proposer.lock().await;
+            let result = proposer.query(&KvStateMachine::scan_query(&req.prefix));
+            let entries = KvStateMachine::decode_result(&result).entries;
+            println!(
+                "exporting {} keys with prefix {:?} at slot {}",
+                entries.len(),
+                req.prefix,
+                proposer.get_last_applied_slot()
+            );
+            entries
+        };
+
+        let mut entries = entries.into_iter();
+        let chunks = std::iter::from_fn(move || {
+            let lines: Vec<String> = entries
+                .by_ref()
+                .take(export::LINES_PER_CHUNK)
+                .map(|entry| export::to_line(&entry))
+                .collect();
+            (!lines.is_empty()).then_some(Ok(ExportChunk { lines }))
+        });
+
+        Ok(Response::new(Box::pin(tokio_stream::iter(chunks))))
+    }
+
+    async fn import(
+        &self,
+        request: Request<Streaming<ImportChunk>>,
+    ) -> Result<Response<ImportResponse>, Status> {
+        let principal = auth::principal(&request).cloned();
+        let mut stream = request.into_inner();
+        let mut batcher = export::Batcher::new();
+        let mut reply = ImportResponse::default();
+        let mut line_number = 0;
+
+        loop {
+            let (lines, done) = match stream.message().await? {
+                Some(chunk) => (chunk.lines, false),
+                None => (vec![], true),
+            };
+            let mut batches = self.import_lines(
+                principal.as_ref(),
+                &mut batcher,
+                lines,
+                &mut line_number,
+                reply.imported,
+            )?;
+            if done {
+                batches.extend(batcher.flush());
+            }
+
+            for batch in batches {
+                let keys = batch.len() as u64;
+                let command = KvStateMachine::batch_command(batch);
+                if !self.execute_command(command, None).await?.chosen {
+                    return Err(Status::aborted(format!(
+                        "batch {} was not chosen, imported {} keys before it",
+                        reply.batches + 1,
+                        reply.imported
+                    )));
                 }
+                reply.imported += keys;
+                reply.batches += 1;
+            }
+            if done {
+                break;
             }
         }
-        println!("{} {} {}", accept.len(), nodes.len(), (nodes.len() / 2));
-        if accept.len() >= nodes.len() / 2 {
-            println!("majority reached");
-            for message in accept {
-                let node = nodes
-                    .iter()
-                    .find(|node| node.node_id == message.node_id)
-                    .unwrap();
-
-                let channel =
-                    Channel::from_shared(format!("http://{}", node.addr.clone().to_string()))
-                        .unwrap()
-                        .connect()
-                        .await
-                        .unwrap();
-
-                let mut client = PaxosClient::new(channel);
-                if message.promised_proposal_id == proposal.clone().proposal_id {
-                    let AcceptMessage = AcceptorRequest {
-                        proposal_id: message.promised_proposal_id,
-                        key: proposal.clone().key,
-                        value: proposal.clone().value,
-                    };
-
-                    let accept = client.accept(AcceptMessage);
-
-                    match accept.await {
-                        Ok(response) => {
-                            println!("accept is here");
-                            let accept = response.into_inner();
-                            println!(
-                                "accept proposal {} and current proposal {}",
-                                accept.proposal_id,
-                                proposal.clone().proposal_id
-                            );
-                            if accept.proposal_id == proposal.clone().proposal_id {
-                                let CommitMessage = LearnerRequest {
-                                    proposal_id: accept.proposal_id,
-                                    key: proposal.clone().key,
-                                    value: proposal.clone().value,
-                                };
-                                client.commit(CommitMessage).await.unwrap();
+        println!(
+            "imported {} keys in {} batches",
+            reply.imported, reply.batches
+        );
+
+        Ok(Response::new(reply))
+    }
+}
+
+#[tonic::async_trait]
+impl ClusterAdmin for PaxosService {
+    type backupStream = BackupStream;
+
+    async fn send_register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        self.authorize(&request, Permission::Admin)?;
+        let clusters = self.clusters.lock().await;
+        let leader_id = self
+            .leader_id
+            .lock()
+            .await
+            .ok_or_else(|| Status::unavailable("leader is not known"))?;
+        let leader_node = clusters
+            .get(&leader_id)
+            .ok_or_else(|| Status::not_found(format!("leader {} is not registered", leader_id)))?;
+
+        println!("leader addr is {}", leader_node.addr);
+        let mut client = self
+            .connections
+            .get_client(leader_node.node_id, leader_node.addr)
+            .await?;
+
+        let request = RegisterRequest {
+            node_id: self.node_id,
+            addr: self.addr.to_string(),
+            status: 0,
+            replaces_node_id: request.get_ref().replaces_node_id,
+            role: ProtoNodeRole::from(*self.role.lock().await) as i32,
+        };
+        let leader_node_id = leader_node.node_id;
+        drop(clusters);
+
+        let register_status = match client.register(request).await {
+            Ok(response) => {
+                self.connections.report_success(leader_node_id).await;
+                println!("register operation successful {:?}", response);
+                let response = response.into_inner();
+                // adopt the membership the leader just committed
+                if response.register_status {
+                    let mut clusters = self.clusters.lock().await;
+                    for member in response.members.iter() {
+                        match member.addr.parse() {
+                            Ok(addr) => match member.role() {
+                                ProtoNodeRole::Voter => ConfigChange::AddVoter {
+                                    node_id: member.node_id,
+                                    addr,
+                                },
+                                ProtoNodeRole::Learner => ConfigChange::AddLearner {
+                                    node_id: member.node_id,
+                                    addr,
+                                },
+                            }
+                            .apply(&mut clusters),
+                            Err(e) => {
+                                eprintln!("member {} has invalid address {}", member.node_id, e)
                             }
                         }
-                        Err(e) => {}
                     }
                 }
+                response.register_status
             }
-        } else {
-            println!("majority not reached");
-        }
+            Err(e) => {
+                self.connections.report_failure(leader_node_id).await;
+                println!("register failed {}", e);
+                false
+            }
+        };
 
-        let reply = InsertResponse { result: true };
+        let reply = RegisterResponse {
+            register_status,
+            members: self.members().await,
+            leader_id: *self.leader_id.lock().await,
+        };
 
         Ok(Response::new(reply))
     }
 
-    async fn propose(
+    async fn promote(
         &self,
-        request: Request<ProposerRequest>,
-    ) -> Result<Response<ProposerResponse>, Status> {
+        request: Request<PromoteRequest>,
+    ) -> Result<Response<PromoteResponse>, Status> {
         let req = request.get_ref();
+        self.authorize(&request, Permission::Admin)?;
 
-        println!("incoming request {:?}", req.clone());
-
-        let proposal = Proposal::new(req.proposal_id, req.key.clone(), req.value.clone());
-
-        let mut proposer = self.proposer.lock().await;
+        let promoted = self.promote_learner(req.node_id).await?;
+        let reply = PromoteResponse { promoted };
 
-        let promise = proposer.prepare(proposal).unwrap();
+        Ok(Response::new(reply))
+    }
 
-        println!("{:?}", promise);
+    async fn remove_node(
+        &self,
+        request: Request<RemoveNodeRequest>,
+    ) -> Result<Response<RemoveNodeResponse>, Status> {
+        let req = request.get_ref();
+        self.authorize(&request, Permission::Admin)?;
 
-        //self.proposer.prepare(proposal)
+        if let Some(mut leader) = self.leader_client().await? {
+            return leader.remove_member(*req).await;
+        }
 
-        let reply = ProposerResponse {
-            node_id: self.node_id.clone(),
-            promised_proposal_id: promise.get_promised_proposal_id(),
-            accepted_proposal_id: promise.get_accepted_proposal_id(),
-            accepted_value: promise.get_accepted_value(),
-        };
+        let removed = self.remove_node_by_id(req.node_id).await?;
+        let reply = RemoveNodeResponse { removed };
 
         Ok(Response::new(reply))
     }
 
-    async fn accept(
+    async fn backup(
         &self,
-        request: Request<AcceptorRequest>,
-    ) -> Result<Response<AcceptorResponse>, Status> {
+        request: Request<BackupRequest>,
+    ) -> Result<Response<Self::backupStream>, Status> {
+        self.authorize(&request, Permission::Admin)?;
+        Ok(Response::new(self.stream_backup().await?))
+    }
+
+    async fn backup_to_file(
+        &self,
+        request: Request<BackupToFileRequest>,
+    ) -> Result<Response<BackupToFileResponse>, Status> {
         let req = request.get_ref();
+        self.authorize(&request, Permission::Admin)?;
+        let backup_dir = self
+            .backup_dir
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("BACKUP_DIR is not configured"))?;
+        let path = backup::resolve(backup_dir, &req.path)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.check_integrity().await?;
 
-        let mut proposer = self.proposer.lock().await;
+        let backup = backup::take(
+            &mut *self.proposer.lock().await,
+            &self.cluster_id,
+            self.node_id,
+        )
+        .map_err(|e| Status::internal(format!("backup failed {}", e)))?;
+        let size = backup::write_file(&path, &backup).map_err(|e| {
+            Status::internal(format!("cannot write backup to {} {}", path.display(), e))
+        })?;
+        println!(
+            "backup at slot {} written to {}",
+            backup.applied_slot,
+            path.display()
+        );
 
-        let proposal = Proposal::new(req.proposal_id, req.key.clone(), req.value.clone());
+        let reply = BackupToFileResponse {
+            path: path.to_string_lossy().into_owned(),
+            applied_slot: backup.applied_slot,
+            size,
+        };
 
-        let m_proposal = ProposerRequest {
-            proposal_id: req.proposal_id.clone(),
-            key: req.key.clone(),
-            value: req.value.clone(),
+        Ok(Response::new(reply))
+    }
+
+    async fn status(
+        &self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        self.authorize(&request, Permission::Admin)?;
+        let (ballot, last_accepted_slot, last_committed_slot, last_applied_slot, needs_resync) = {
+            let proposer = self.proposer.lock().await;
+            (
+                proposer.get_ballot(),
+                proposer.get_last_accepted_slot(),
+                proposer.get_last_committed_slot(),
+                proposer.get_last_applied_slot(),
+                proposer.needs_resync(),
+            )
         };
 
-        let accept = proposer.accept(proposal).unwrap();
+        let peers = self
+            .clusters
+            .lock()
+            .await
+            .values()
+            .filter(|node| node.node_id != self.node_id)
+            .map(|node| PeerStatus {
+                node_id: node.node_id,
+                addr: node.addr.to_string(),
+                status: ProtoConnectionStatus::from(node.status) as i32,
+                last_seen_ms_ago: node.last_seen.map(|seen| seen.elapsed().as_millis() as u64),
+                rtt_ms: node.rtt.map(|rtt| rtt.as_millis() as u64),
+                last_applied_slot: node.applied_slot,
+                replication_lag: (last_applied_slot - node.applied_slot).max(0),
+                role: ProtoNodeRole::from(node.role) as i32,
+                unreachable_ms: node
+                    .unreachable_since
+                    .map(|since| since.elapsed().as_millis() as u64),
+                diverged_slot: node.diverged_slot,
+            })
+            .collect();
 
-        let reply = AcceptorResponse {
-            node_id: self.node_id.clone(),
-            status: 1 as i32,
-            proposal_id: accept.proposal_id,
-            proposal: Some(m_proposal),
+        let reply = StatusResponse {
+            node_id: self.node_id,
+            leader_id: *self.leader_id.lock().await,
+            ballot,
+            last_accepted_slot,
+            last_committed_slot,
+            last_applied_slot,
+            peers,
+            needs_resync,
+            consistency_checks: self.consistency_checks.load(Ordering::SeqCst),
+            divergences: self.divergences.load(Ordering::SeqCst),
         };
 
         Ok(Response::new(reply))
     }
 
-    async fn commit(
+    async fn transfer_leadership(
         &self,
-        request: Request<LearnerRequest>,
-    ) -> Result<Response<LearnerResponse>, Status> {
+        request: Request<TransferLeadershipRequest>,
+    ) -> Result<Response<TransferLeadershipResponse>, Status> {
         let req = request.get_ref();
-        let mut proposer = self.proposer.lock().await;
-
-        let proposal = Proposal::new(req.proposal_id, req.key.clone(), req.value.clone());
+        self.authorize(&request, Permission::Admin)?;
 
-        proposer.commit(proposal);
-        let reply = LearnerResponse {
-            node_id: self.node_id.clone(),
-            status: true,
+        self.transfer_leadership_to(req.target_node_id).await?;
+        let reply = TransferLeadershipResponse {
+            success: true,
+            leader_id: *self.leader_id.lock().await,
         };
+
         Ok(Response::new(reply))
     }
 }
 
+impl PaxosService {
+    /// Refuses phase 1 and 2 for a slot this node already applied. Its
+    /// acceptor state is gone, voting again could choose a second value.
+    fn check_slot_open(proposer: &Proposer, slot: i64) -> Result<(), Status> {
+        if proposer.is_applied(slot) {
+            return Err(Status::failed_precondition(format!(
+                "slot {} was already chosen, applied up to {}",
+                slot,
+                proposer.get_last_applied_slot()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Batches completed by the lines of an import chunk. Fails on the
+    /// first malformed line or key the caller may not write, before any of
+    /// the chunk is proposed.
+    fn import_lines(
+        &self,
+        principal: Option<&Principal>,
+        batcher: &mut export::Batcher,
+        lines: Vec<String>,
+        line_number: &mut u64,
+        imported: u64,
+    ) -> Result<Vec<Vec<(String, String)>>, Status> {
+        let mut batches = vec![];
+        for line in lines {
+            *line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = export::parse_line(&line).map_err(|e| {
+                Status::invalid_argument(format!(
+                    "line {} {}, imported {} keys before it",
+                    line_number, e, imported
+                ))
+            })?;
+            self.authorize_principal(principal, Permission::Key(Access::Write, &key))?;
+            batches.extend(batcher.push(key, value));
+        }
+        Ok(batches)
+    }
+}
+
+#[cfg(test)]
+impl PaxosService {
+    /// Node 1, alone in its cluster and keeping its state in memory.
+    pub(super) fn for_tests() -> Self {
+        use crate::auth::BearerToken;
+        use crate::discovery::StaticDiscovery;
+        use crate::store::MemoryStore;
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let mut clusters = Membership::new();
+        clusters.insert(1, NodeConfig::new(1, addr, ConnectionStatus::Active));
+        let state_machine = KvStateMachine::new(Box::new(MemoryStore::new()));
+        Self::new(
+            1,
+            String::from("cluster"),
+            addr,
+            NodeRole::Voter,
+            Arc::new(Mutex::new(clusters)),
+            Arc::new(ConnectionManager::new(None, BearerToken::default())),
+            Arc::new(Mutex::new(Proposer::new(Box::new(state_machine)))),
+            Some(1),
+            Arc::new(StaticDiscovery::new(vec![])),
+        )
+    }
+}
+
+/// Counts a write as in flight until dropped, so shutdown can drain it.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl From<NodeRole> for ProtoNodeRole {
+    fn from(role: NodeRole) -> Self {
+        match role {
+            NodeRole::Voter => ProtoNodeRole::Voter,
+            NodeRole::Learner => ProtoNodeRole::Learner,
+        }
+    }
+}
+
+impl From<ProtoNodeRole> for NodeRole {
+    fn from(role: ProtoNodeRole) -> Self {
+        match role {
+            ProtoNodeRole::Voter => NodeRole::Voter,
+            ProtoNodeRole::Learner => NodeRole::Learner,
+        }
+    }
+}
+
+impl From<ConnectionStatus> for ProtoConnectionStatus {
+    fn from(status: ConnectionStatus) -> Self {
+        match status {
+            ConnectionStatus::Active => ProtoConnectionStatus::Active,
+            ConnectionStatus::Unreachable => ProtoConnectionStatus::Unreachable,
+            ConnectionStatus::Retrying => ProtoConnectionStatus::Retrying,
+        }
+    }
+}
+
 impl NodeConfig {
-    fn new(node_id: i32, addr: SocketAddr, status: Connection_Status) -> Self {
+    pub fn new(node_id: i32, addr: SocketAddr, status: ConnectionStatus) -> Self {
         Self {
             node_id,
             addr,
             status,
+            last_seen: None,
+            rtt: None,
+            applied_slot: 0,
+            failures: 0,
+            unreachable_since: None,
+            role: NodeRole::Voter,
+            diverged_slot: None,
+        }
+    }
+
+    pub fn set_role(&mut self, role: NodeRole) {
+        self.role = role;
+    }
+
+    pub fn is_voter(&self) -> bool {
+        self.role == NodeRole::Voter
+    }
+
+    pub fn get_node_id(&self) -> i32 {
+        self.node_id
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Moves the node to `addr`. Health starts over at the new address so
+    /// the old address' failures do not count against it.
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        if self.addr == addr {
+            return;
+        }
+        println!("node {} moved from {} to {}", self.node_id, self.addr, addr);
+        self.addr = addr;
+        self.failures = 0;
+        self.last_seen = None;
+        self.rtt = None;
+        self.set_status(ConnectionStatus::Active);
+    }
+
+    pub fn get_status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    pub fn get_applied_slot(&self) -> i64 {
+        self.applied_slot
+    }
+
+    /// First slot of the earliest range where this node's state hash
+    /// differed from ours at the last consistency check.
+    pub fn get_diverged_slot(&self) -> Option<i64> {
+        self.diverged_slot
+    }
+
+    pub fn set_diverged_slot(&mut self, diverged_slot: Option<i64>) {
+        self.diverged_slot = diverged_slot;
+    }
+
+    /// Marks the peer `Active` after a successful ping.
+    pub fn record_success(&mut self, rtt: Duration, applied_slot: i64) {
+        self.failures = 0;
+        self.last_seen = Some(Instant::now());
+        self.rtt = Some(rtt);
+        self.applied_slot = applied_slot;
+        self.set_status(ConnectionStatus::Active);
+    }
+
+    /// Counts a failed ping, moving the peer to `Retrying` and then to
+    /// `Unreachable` once `unreachable_after` consecutive pings failed.
+    pub fn record_failure(&mut self, unreachable_after: u32) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= unreachable_after {
+            self.set_status(ConnectionStatus::Unreachable);
+        } else {
+            self.set_status(ConnectionStatus::Retrying);
+        }
+    }
+
+    fn set_status(&mut self, status: ConnectionStatus) {
+        if self.status != status {
+            println!(
+                "node {} status changed {:?} -> {:?}",
+                self.node_id, self.status, status
+            );
+            self.status = status;
+            self.unreachable_since =
+                Some(Instant::now()).filter(|_| status == ConnectionStatus::Unreachable);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> NodeConfig {
+        NodeConfig::new(
+            2,
+            SocketAddr::from(([10, 0, 0, 2], 9000)),
+            ConnectionStatus::Active,
+        )
+    }
+
+    #[test]
+    fn failures_retry_then_mark_unreachable() {
+        let mut node = node();
+        node.record_failure(3);
+        assert_eq!(node.get_status(), ConnectionStatus::Retrying);
+        node.record_failure(3);
+        assert_eq!(node.get_status(), ConnectionStatus::Retrying);
+        assert!(node.unreachable_since.is_none());
+        node.record_failure(3);
+        assert_eq!(node.get_status(), ConnectionStatus::Unreachable);
+        let since = node.unreachable_since.unwrap();
+
+        // further failures keep the time it became unreachable
+        node.record_failure(3);
+        assert_eq!(node.unreachable_since, Some(since));
+    }
+
+    #[test]
+    fn threshold_of_one_skips_retrying() {
+        let mut node = node();
+        node.record_failure(1);
+        assert_eq!(node.get_status(), ConnectionStatus::Unreachable);
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let mut node = node();
+        node.record_failure(3);
+        node.record_failure(3);
+        node.record_success(Duration::from_millis(4), 17);
+        assert_eq!(node.get_status(), ConnectionStatus::Active);
+        assert_eq!(node.rtt, Some(Duration::from_millis(4)));
+        assert_eq!(node.get_applied_slot(), 17);
+        assert!(node.last_seen.is_some());
+
+        node.record_failure(3);
+        node.record_failure(3);
+        assert_eq!(node.get_status(), ConnectionStatus::Retrying);
+        node.record_failure(3);
+        node.record_success(Duration::from_millis(4), 18);
+        assert_eq!(node.get_status(), ConnectionStatus::Active);
+        assert!(node.unreachable_since.is_none());
+    }
+
+    #[tokio::test]
+    async fn status_reports_peers_and_their_lag() {
+        let service = PaxosService::for_tests();
+        for slot in 1..=12 {
+            service
+                .proposer
+                .lock()
+                .await
+                .commit(Proposal::new(1, vec![]).with_slot(slot));
+        }
+        {
+            let mut clusters = service.clusters.lock().await;
+            let mut behind = node();
+            behind.record_success(Duration::from_millis(3), 9);
+            clusters.insert(2, behind);
+            let mut lost = NodeConfig::new(
+                3,
+                SocketAddr::from(([10, 0, 0, 3], 9000)),
+                ConnectionStatus::Active,
+            );
+            lost.record_failure(1);
+            clusters.insert(3, lost);
+        }
+
+        let status = ClusterAdmin::status(&service, Request::new(StatusRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((status.node_id, status.leader_id), (1, Some(1)));
+        assert_eq!(status.last_applied_slot, 12);
+        assert_eq!(status.last_committed_slot, 12);
+        assert!(!status.needs_resync);
+
+        let peers: Vec<_> = status
+            .peers
+            .iter()
+            .map(|peer| (peer.node_id, peer.status(), peer.replication_lag))
+            .collect();
+        assert_eq!(
+            peers,
+            vec![
+                (2, ProtoConnectionStatus::Active, 3),
+                (3, ProtoConnectionStatus::Unreachable, 12)
+            ]
+        );
+        assert_eq!(status.peers[0].rtt_ms, Some(3));
+        assert!(status.peers[0].unreachable_ms.is_none());
+        assert!(status.peers[1].unreachable_ms.is_some());
+        assert!(status.peers[1].last_seen_ms_ago.is_none());
+    }
+
+    #[tokio::test]
+    async fn export_streams_without_holding_the_lock() {
+        use tokio_stream::StreamExt;
+
+        let service = PaxosService::for_tests();
+        let pairs = (0..=export::LINES_PER_CHUNK)
+            .map(|i| (format!("k/{:04}", i), String::from("v")))
+            .collect();
+        service
+            .proposer
+            .lock()
+            .await
+            .commit(Proposal::new(1, KvStateMachine::batch_command(pairs)).with_slot(1));
+
+        let request = Request::new(ExportRequest {
+            prefix: String::from("k/"),
+        });
+        let mut stream = KvClient::export(&service, request)
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(service.proposer.try_lock().is_ok());
+
+        let mut chunks = vec![];
+        while let Some(chunk) = stream.next().await {
+            chunks.push(chunk.unwrap().lines.len());
         }
+        assert_eq!(chunks, vec![export::LINES_PER_CHUNK, 1]);
     }
 }
@@ -0,0 +1,361 @@
+use tonic::Status;
+
+use std::sync::atomic::Ordering;
+
+use super::{ConnectionStatus, InFlight, NodeRole, PaxosService};
+use crate::acceptor::AcceptorStatus;
+use crate::connection::PeerClient;
+use crate::proposal::{Proposal, RequestId};
+use crate::proto::{AcceptorRequest, ExecuteResponse, LearnerRequest};
+use crate::quorum::Quorum;
+
+impl PaxosService {
+    /// Replicates a client command. A retry of the client's last applied
+    /// command gets the cached result instead of being proposed again.
+    pub(super) async fn execute_command(
+        &self,
+        command: Vec<u8>,
+        request_id: Option<RequestId>,
+    ) -> Result<ExecuteResponse, Status> {
+        // counted before the checks below: `drain` sets `draining` and then
+        // waits for the count, so either it sees this write or it is refused
+        let _in_flight = InFlight::new(self.in_flight.clone());
+        if *self.role.lock().await == NodeRole::Learner {
+            return Err(Status::failed_precondition(
+                "learner nodes do not accept writes",
+            ));
+        }
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("node is shutting down"));
+        }
+        if self.transferring.load(Ordering::SeqCst) {
+            return Err(Status::unavailable("leadership transfer in progress"));
+        }
+        // a former leader must not keep proposing after a transfer
+        let leader_id = *self.leader_id.lock().await;
+        if leader_id != Some(self.node_id) {
+            return Err(Status::failed_precondition(match leader_id {
+                Some(leader_id) => format!(
+                    "node {} is not the leader, send writes to node {}",
+                    self.node_id, leader_id
+                ),
+                None => format!(
+                    "node {} is not the leader, leader is not known",
+                    self.node_id
+                ),
+            }));
+        }
+        self.check_integrity().await?;
+
+        if let Some(request_id) = &request_id {
+            let session = self
+                .proposer
+                .lock()
+                .await
+                .get_session(request_id.get_client_id());
+            match session {
+                Some(session) if request_id.get_sequence() == session.get_sequence() => {
+                    return Ok(ExecuteResponse {
+                        chosen: true,
+                        result: session.get_result().to_vec(),
+                        duplicate: true,
+                    });
+                }
+                Some(session) if request_id.get_sequence() < session.get_sequence() => {
+                    return Err(Status::failed_precondition(format!(
+                        "request {} of client {} was superseded by {}",
+                        request_id.get_sequence(),
+                        request_id.get_client_id(),
+                        session.get_sequence()
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        let proposal = Proposal::new(0, command).with_request_id(request_id);
+        let result = self.replicate(proposal).await?;
+
+        Ok(ExecuteResponse {
+            chosen: result.is_some(),
+            result: result.unwrap_or_default(),
+            duplicate: false,
+        })
+    }
+
+    /// Replicates `value` and returns the state machine's result, `None`
+    /// when it was not chosen. Slots already holding an accepted value get
+    /// that value chosen first, `value` then moves on to the next slot.
+    pub(super) async fn replicate(&self, value: Proposal) -> Result<Option<Vec<u8>>, Status> {
+        // the slot stays reserved until its value is committed, so two
+        // writes on this node never propose into the same slot
+        let _proposing = self.proposing.lock().await;
+        loop {
+            match self.replicate_slot(&value).await? {
+                Some(Chosen {
+                    adopted: false,
+                    result,
+                }) => return Ok(Some(result)),
+                Some(Chosen { adopted: true, .. }) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /*
+     * prepare on the local acceptor and send propose all peers
+     * calculate majority promise over every voter including this node
+     * propose the value accepted with the highest ballot among the promises,
+     * our own value only when nothing was accepted in the slot yet
+     * accept on the local acceptor and send accept to promised peers
+     * commit locally and on accepted peers once a majority accepted
+     */
+    async fn replicate_slot(&self, value: &Proposal) -> Result<Option<Chosen>, Status> {
+        // snapshot the voters so the cluster lock is not held across rpcs
+        let (mut peers, learners): (Vec<_>, Vec<_>) = self
+            .clusters
+            .lock()
+            .await
+            .values()
+            .filter(|node| node.node_id != self.node_id)
+            .map(|node| (node.node_id, node.addr, node.status, node.role))
+            .partition(|(_, _, _, role)| *role == NodeRole::Voter);
+        let quorum = Quorum::with_local(peers.len());
+        println!(
+            "{} voters, majority is {}",
+            quorum.get_voters(),
+            quorum.get_size()
+        );
+
+        let (propose_id, slot) = {
+            let mut proposer = self.proposer.lock().await;
+            let ballot = proposer.get_ballot() + 1;
+            proposer.set_last_seen_propose_id(ballot);
+            (ballot, proposer.get_next_slot())
+        };
+        println!("new propose id {} for slot {}", propose_id, slot);
+        let proposal = value.clone().with_proposal_id(propose_id).with_slot(slot);
+
+        // phase 1: this node promises through its own acceptor
+        let mut promises: usize = 0;
+        let mut accepted: Option<Proposal> = None;
+        if let Some(promise) = self.proposer.lock().await.prepare(proposal.clone()) {
+            promises += 1;
+            accepted = promise.get_accepted_proposal().cloned();
+        }
+
+        // peers known to be down are only contacted when the healthy ones
+        // cannot form a majority on their own
+        peers.sort_by_key(|(_, _, status, _)| match status {
+            ConnectionStatus::Active => 0,
+            ConnectionStatus::Retrying => 1,
+            ConnectionStatus::Unreachable => 2,
+        });
+
+        let mut promised_peers: Vec<(i32, PeerClient)> = vec![];
+        for (peer_id, addr, status, _) in peers {
+            if status == ConnectionStatus::Unreachable && quorum.is_reached(promises) {
+                println!("node {} is unreachable, skipped", peer_id);
+                continue;
+            }
+            println!("node id is {}", peer_id);
+            let mut client = match self.connections.get_client(peer_id, addr).await {
+                Ok(client) => client,
+                Err(e) => {
+                    println!("node {} skipped {}", peer_id, e);
+                    continue;
+                }
+            };
+            match client.propose(proposal.to_proto()).await {
+                Ok(response) => {
+                    self.connections.report_success(peer_id).await;
+                    let message = response.into_inner();
+                    println!("response {:?}", message.clone());
+                    if message.promised_proposal_id != propose_id {
+                        continue;
+                    }
+                    promises += 1;
+                    promised_peers.push((peer_id, client));
+                    match message.accepted.as_ref().map(Proposal::from_proto) {
+                        Some(Ok(peer_accepted))
+                            if accepted.as_ref().is_none_or(|accepted| {
+                                peer_accepted.get_proposal_id() > accepted.get_proposal_id()
+                            }) =>
+                        {
+                            accepted = Some(peer_accepted)
+                        }
+                        Some(Err(e)) => println!("node {} sent a malformed value {}", peer_id, e),
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    self.connections.report_failure(peer_id).await;
+                    println!("propose to node {} failed {}", peer_id, e)
+                }
+            }
+        }
+
+        if !quorum.is_reached(promises) {
+            println!("majority not reached, {} promises", promises);
+            return Ok(None);
+        }
+        println!("majority reached, {} promises", promises);
+
+        // a value may already be chosen in the slot, only it can be proposed
+        let adopted = accepted.is_some();
+        let proposal = match accepted {
+            Some(accepted) => {
+                println!(
+                    "slot {} holds a value accepted with ballot {}, proposing it",
+                    slot,
+                    accepted.get_proposal_id()
+                );
+                accepted.with_proposal_id(propose_id).with_slot(slot)
+            }
+            None => proposal,
+        };
+        let request = proposal.to_proto();
+
+        // phase 2: accept locally, then on every peer that promised
+        let mut accepts: usize = 0;
+        if let Some(message) = self.proposer.lock().await.accept(proposal.clone()) {
+            if message.status == AcceptorStatus::Accepted {
+                accepts += 1;
+            }
+        }
+
+        let mut accepted_peers: Vec<(i32, PeerClient)> = vec![];
+        for (peer_id, mut client) in promised_peers {
+            let accept_message = AcceptorRequest {
+                proposal_id: request.proposal_id,
+                command: request.command.clone(),
+                slot,
+                config: request.config.clone(),
+                request_id: request.request_id.clone(),
+            };
+
+            match client.accept(accept_message).await {
+                Ok(response) => {
+                    let accept = response.into_inner();
+                    println!(
+                        "accept proposal {} and current proposal {}",
+                        accept.proposal_id, request.proposal_id
+                    );
+                    if accept.proposal_id == request.proposal_id {
+                        accepts += 1;
+                        accepted_peers.push((peer_id, client));
+                    }
+                }
+                Err(e) => {
+                    self.connections.report_failure(peer_id).await;
+                    println!("accept on node {} failed {}", peer_id, e)
+                }
+            }
+        }
+
+        if !quorum.is_reached(accepts) {
+            println!("majority not reached, {} accepts", accepts);
+            return Ok(None);
+        }
+
+        // phase 3: the value is chosen, learn it everywhere it was accepted
+        // and on every learner
+        let result = self.apply_commit(proposal).await;
+        for (peer_id, addr, _, _) in learners {
+            match self.connections.get_client(peer_id, addr).await {
+                Ok(client) => accepted_peers.push((peer_id, client)),
+                Err(e) => println!("learner {} skipped {}", peer_id, e),
+            }
+        }
+        for (peer_id, mut client) in accepted_peers {
+            let commit_message = LearnerRequest {
+                proposal_id: request.proposal_id,
+                command: request.command.clone(),
+                slot,
+                config: request.config.clone(),
+                request_id: request.request_id.clone(),
+            };
+            if let Err(e) = client.commit(commit_message).await {
+                self.connections.report_failure(peer_id).await;
+                println!("commit on node {} failed {}", peer_id, e);
+            }
+        }
+
+        Ok(Some(Chosen {
+            adopted,
+            // chosen but waiting for a slot this node missed
+            result: result.unwrap_or_default(),
+        }))
+    }
+}
+
+/// A value chosen in a slot by `replicate_slot`.
+struct Chosen {
+    // the slot already held an accepted value, it was chosen instead
+    adopted: bool,
+    result: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_machine::KvStateMachine;
+
+    fn put(key: &str, value: &str) -> Proposal {
+        Proposal::new(0, KvStateMachine::set_command(key, value))
+    }
+
+    async fn read(service: &PaxosService, key: &str) -> Option<String> {
+        let result = service
+            .proposer
+            .lock()
+            .await
+            .query(&KvStateMachine::get_query(key));
+        KvStateMachine::decode_result(&result).value
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_take_their_own_slots() {
+        let service = PaxosService::for_tests();
+        let (a, b) = tokio::join!(
+            service.replicate(put("a", "1")),
+            service.replicate(put("b", "2"))
+        );
+        assert!(a.unwrap().is_some() && b.unwrap().is_some());
+
+        assert_eq!(service.proposer.lock().await.get_last_applied_slot(), 2);
+        assert_eq!(read(&service, "a").await, Some(String::from("1")));
+        assert_eq!(read(&service, "b").await, Some(String::from("2")));
+    }
+
+    #[tokio::test]
+    async fn only_the_leader_takes_writes() {
+        let service = PaxosService::for_tests();
+        *service.leader_id.lock().await = Some(2);
+
+        let command = KvStateMachine::set_command("k", "v");
+        let status = service.execute_command(command, None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(service.proposer.lock().await.get_last_applied_slot(), 0);
+    }
+
+    #[tokio::test]
+    async fn value_accepted_in_the_slot_is_chosen_first() {
+        let service = PaxosService::for_tests();
+        // accepted in an earlier round that never reached the commit
+        service
+            .proposer
+            .lock()
+            .await
+            .accept(put("earlier", "1").with_proposal_id(1).with_slot(1));
+
+        assert!(service
+            .replicate(put("later", "2"))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(service.proposer.lock().await.get_last_applied_slot(), 2);
+        assert_eq!(read(&service, "earlier").await, Some(String::from("1")));
+        assert_eq!(read(&service, "later").await, Some(String::from("2")));
+    }
+}
@@ -0,0 +1,123 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::Status;
+
+use super::{ConnectionStatus, PaxosService};
+use crate::backup;
+use crate::proto::{Backup, BackupRequest};
+
+impl PaxosService {
+    /// Fails with `data_loss` while the local state failed checksum
+    /// verification. Such a node neither votes nor serves reads until it
+    /// has been re-synced from a healthy peer.
+    pub(super) async fn check_integrity(&self) -> Result<(), Status> {
+        if self.proposer.lock().await.is_corrupted() {
+            return Err(Status::data_loss(
+                "local state failed checksum verification, waiting for re-sync",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether this node has to be restored from a peer: its state is
+    /// corrupted, it missed a chosen slot, or it leads while an active voter
+    /// applied more. A leader that fell behind cannot propose, its peers
+    /// refuse the slots they already applied.
+    pub(super) async fn needs_resync(&self) -> bool {
+        let applied_slot = {
+            let proposer = self.proposer.lock().await;
+            if proposer.needs_resync() {
+                return true;
+            }
+            proposer.get_last_applied_slot()
+        };
+        if *self.leader_id.lock().await != Some(self.node_id) {
+            return false;
+        }
+        self.clusters.lock().await.values().any(|node| {
+            node.node_id != self.node_id
+                && node.is_voter()
+                && node.status == ConnectionStatus::Active
+                && node.applied_slot > applied_slot
+        })
+    }
+
+    /*
+     * a leader hands leadership off first
+     * fetch a backup from the leader, then from any other active voter
+     * restore it unless this node applied past it while fetching
+     */
+    pub(super) async fn resync(&self) -> Result<(), Status> {
+        self.hand_off_leadership().await;
+
+        let leader_id = *self.leader_id.lock().await;
+        let mut peers: Vec<(i32, SocketAddr)> = self
+            .clusters
+            .lock()
+            .await
+            .values()
+            .filter(|node| node.node_id != self.node_id)
+            .filter(|node| node.status == ConnectionStatus::Active && node.is_voter())
+            .map(|node| (node.node_id, node.addr))
+            .collect();
+        peers.sort_by_key(|(peer_id, _)| Some(*peer_id) != leader_id);
+
+        for (peer_id, addr) in peers {
+            let backup = match self.fetch_backup(peer_id, addr).await {
+                Ok(backup) => backup,
+                Err(e) => {
+                    println!("cannot fetch backup from node {} {}", peer_id, e);
+                    continue;
+                }
+            };
+            let mut proposer = self.proposer.lock().await;
+            if backup.applied_slot < proposer.get_last_applied_slot() {
+                println!(
+                    "backup of node {} at slot {} is behind local slot {}",
+                    peer_id,
+                    backup.applied_slot,
+                    proposer.get_last_applied_slot()
+                );
+                continue;
+            }
+            backup::restore(&mut proposer, backup, &self.cluster_id)
+                .map_err(|e| Status::internal(format!("re-sync restore failed {}", e)))?;
+            println!("re-synced from node {}", peer_id);
+            return Ok(());
+        }
+        Err(Status::unavailable("no healthy peer to re-sync from"))
+    }
+
+    async fn fetch_backup(&self, peer_id: i32, addr: SocketAddr) -> Result<Backup, Status> {
+        let mut client = self.connections.get_client(peer_id, addr).await?;
+        let mut stream = client.backup(BackupRequest {}).await?.into_inner();
+        let mut chunks = vec![];
+        while let Some(chunk) = stream.message().await? {
+            chunks.push(chunk);
+        }
+        backup::assemble(chunks).map_err(|e| Status::data_loss(e.to_string()))
+    }
+}
+
+/// How often a node checks whether it needs a re-sync.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Re-syncs this node from a peer whenever it needs to, see
+/// `PaxosService::needs_resync`.
+pub fn spawn_resync(paxos: Arc<PaxosService>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RESYNC_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if !paxos.needs_resync().await {
+                continue;
+            }
+            eprintln!("local state is corrupted or behind, re-syncing from a peer");
+            if let Err(e) = paxos.resync().await {
+                eprintln!("re-sync failed {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::Status;
+
+use super::{ConnectionStatus, PaxosService};
+use crate::proto::{RangeHash, StateHashRequest};
+
+impl PaxosService {
+    /*
+     * fetch the range hashes of every active peer
+     * compare the ranges both sides completed with the local ones
+     * alert when a peer starts diverging and when it agrees again
+     */
+    pub(super) async fn verify_replicas(&self) {
+        let local: HashMap<i64, (u64, u32)> = self
+            .proposer
+            .lock()
+            .await
+            .get_range_hashes()
+            .into_iter()
+            .map(|range| (range.first_slot, (range.hash, range.entries)))
+            .collect();
+        let peers: Vec<(i32, SocketAddr)> = self
+            .clusters
+            .lock()
+            .await
+            .values()
+            .filter(|node| node.node_id != self.node_id)
+            .filter(|node| node.status == ConnectionStatus::Active)
+            .map(|node| (node.node_id, node.addr))
+            .collect();
+
+        for (peer_id, addr) in peers {
+            let ranges = match self.fetch_range_hashes(peer_id, addr).await {
+                Ok(ranges) => ranges,
+                Err(e) => {
+                    println!("cannot fetch state hash of node {} {}", peer_id, e);
+                    continue;
+                }
+            };
+            self.consistency_checks.fetch_add(1, Ordering::SeqCst);
+            let diverged = ranges
+                .iter()
+                .filter(|range| {
+                    local
+                        .get(&range.first_slot)
+                        .is_some_and(|local| *local != (range.hash, range.entries))
+                })
+                .min_by_key(|range| range.first_slot);
+
+            let mut clusters = self.clusters.lock().await;
+            let node = match clusters.get_mut(&peer_id) {
+                Some(node) => node,
+                None => continue,
+            };
+            match (node.get_diverged_slot(), diverged) {
+                (None, Some(range)) => {
+                    self.divergences.fetch_add(1, Ordering::SeqCst);
+                    eprintln!(
+                        "ALERT replica {} diverges from node {} in slots {}..={}",
+                        peer_id, self.node_id, range.first_slot, range.last_slot
+                    );
+                }
+                (Some(_), None) => println!("replica {} is consistent again", peer_id),
+                _ => {}
+            }
+            node.set_diverged_slot(diverged.map(|range| range.first_slot));
+        }
+    }
+
+    async fn fetch_range_hashes(
+        &self,
+        peer_id: i32,
+        addr: SocketAddr,
+    ) -> Result<Vec<RangeHash>, Status> {
+        let mut client = self.connections.get_client(peer_id, addr).await?;
+        let reply = client.state_hash(StateHashRequest {}).await?.into_inner();
+        Ok(reply.ranges)
+    }
+}
+
+/// Compares this node's state hashes with every peer each `interval`, see
+/// `PaxosService::verify_replicas`.
+pub fn spawn_verifier(paxos: Arc<PaxosService>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            paxos.verify_replicas().await;
+        }
+    });
+}
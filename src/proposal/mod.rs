@@ -1,28 +1,131 @@
+use tonic::Status;
+
+use crate::paxos::ConfigChange;
+use crate::proto;
+
 #[derive(Debug, Clone, Default)]
 pub struct Proposal {
     proposer_id: i32,
-    key: String,
-    value: String,
+    slot: i64,
+    command: Vec<u8>,
+    config: Option<ConfigChange>,
+    request_id: Option<RequestId>,
+}
+
+/// Client id and per-client sequence number of a command. A client issues
+/// increasing sequence numbers and reuses one when it retries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId {
+    client_id: String,
+    sequence: u64,
 }
 
 impl Proposal {
-    pub fn new(proposer_id: i32, key: String, value: String) -> Self {
+    pub fn new(proposer_id: i32, command: Vec<u8>) -> Self {
         Self {
             proposer_id,
-            key,
-            value,
+            slot: 0,
+            command,
+            config: None,
+            request_id: None,
         }
     }
 
+    /// Tags the proposal with the client command it carries.
+    pub fn with_request_id(mut self, request_id: Option<RequestId>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    /// Turns the proposal into a membership command.
+    pub fn with_config(mut self, config: Option<ConfigChange>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Proposes the same value under another ballot.
+    pub fn with_proposal_id(mut self, proposer_id: i32) -> Self {
+        self.proposer_id = proposer_id;
+        self
+    }
+
+    /// Places the proposal in a log slot.
+    pub fn with_slot(mut self, slot: i64) -> Self {
+        self.slot = slot;
+        self
+    }
+
     pub fn get_proposal_id(&self) -> i32 {
         self.proposer_id
     }
 
-    pub fn get_key(&self) -> &str {
-        &self.key
+    pub fn get_slot(&self) -> i64 {
+        self.slot
+    }
+
+    /// State machine command, opaque to consensus.
+    pub fn get_command(&self) -> &[u8] {
+        &self.command
+    }
+
+    pub fn get_config(&self) -> Option<&ConfigChange> {
+        self.config.as_ref()
+    }
+
+    pub fn get_request_id(&self) -> Option<&RequestId> {
+        self.request_id.as_ref()
     }
 
-    pub fn get_value(&self) -> &str {
-        &self.value
+    /// The proposal as sent in phase 1 and returned in promises.
+    pub fn to_proto(&self) -> proto::ProposerRequest {
+        proto::ProposerRequest {
+            proposal_id: self.proposer_id,
+            command: self.command.clone(),
+            slot: self.slot,
+            config: self.config.as_ref().map(ConfigChange::to_proto),
+            request_id: self.request_id.as_ref().map(RequestId::to_proto),
+        }
+    }
+
+    pub fn from_proto(request: &proto::ProposerRequest) -> Result<Self, Status> {
+        let config = request
+            .config
+            .as_ref()
+            .map(ConfigChange::from_proto)
+            .transpose()?;
+        Ok(Proposal::new(request.proposal_id, request.command.clone())
+            .with_slot(request.slot)
+            .with_config(config)
+            .with_request_id(request.request_id.as_ref().map(RequestId::from)))
+    }
+}
+
+impl RequestId {
+    pub fn new(client_id: String, sequence: u64) -> Self {
+        Self {
+            client_id,
+            sequence,
+        }
+    }
+
+    pub fn get_client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn get_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn to_proto(&self) -> proto::RequestId {
+        proto::RequestId {
+            client_id: self.client_id.clone(),
+            sequence: self.sequence,
+        }
+    }
+}
+
+impl From<&proto::RequestId> for RequestId {
+    fn from(request_id: &proto::RequestId) -> Self {
+        RequestId::new(request_id.client_id.clone(), request_id.sequence)
     }
 }
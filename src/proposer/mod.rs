@@ -1,9 +1,12 @@
-use veresiye::Veresiye;
+use std::collections::HashMap;
+use std::io;
 
 use crate::{
-    acceptor::{Accept_Message, Acceptor, Promise},
-    learner::Learner,
+    acceptor::{AcceptMessage, Acceptor, Promise},
+    learner::{Learner, Session},
     proposal::Proposal,
+    state_hash::RangeHash,
+    state_machine::StateMachine,
 };
 
 //#[derive(Default)]
@@ -11,45 +14,137 @@ pub struct Proposer {
     acceptor: Acceptor,
     learner: Learner,
     last_seen_propose_id: i32,
+    last_committed_slot: i64,
 }
 
 impl Proposer {
-    pub fn new(db_path: String) -> Self {
-        let database = Veresiye::new(db_path).unwrap();
+    pub fn new(state_machine: Box<dyn StateMachine>) -> Self {
         let acceptor = Acceptor::new();
-        let learner = Learner::new(database);
+        let learner = Learner::new(state_machine);
         let last_seen_propose_id: i32 = 0;
+        let last_committed_slot = learner.get_last_applied_slot();
 
         Self {
             acceptor,
             learner,
             last_seen_propose_id,
+            last_committed_slot,
         }
     }
 
-    pub fn get_last_seen_propose_id(&self) -> i32 {
-        self.last_seen_propose_id
+    /// Replaces the in-memory acceptor, e.g. with one kept on disk.
+    pub fn with_acceptor(mut self, acceptor: Acceptor) -> Self {
+        self.acceptor = acceptor;
+        self.acceptor
+            .forget_through(self.learner.get_last_applied_slot());
+        self
     }
 
     pub fn set_last_seen_propose_id(&mut self, id: i32) {
         self.last_seen_propose_id = id;
     }
 
+    /// Highest ballot this node has issued or promised.
+    pub fn get_ballot(&self) -> i32 {
+        self.last_seen_propose_id
+            .max(self.acceptor.get_max_seen_proposal_seq())
+    }
+
+    /// Highest ballot the local acceptor has promised.
+    pub fn get_promised_ballot(&self) -> i32 {
+        self.acceptor.get_max_seen_proposal_seq()
+    }
+
+    /// The slot the next proposal from this node should fill.
+    pub fn get_next_slot(&self) -> i64 {
+        self.last_committed_slot + 1
+    }
+
+    pub fn get_last_accepted_slot(&self) -> i64 {
+        self.acceptor.get_last_accepted_slot()
+    }
+
+    pub fn get_last_committed_slot(&self) -> i64 {
+        self.last_committed_slot
+    }
+
+    pub fn get_last_applied_slot(&self) -> i64 {
+        self.learner.get_last_applied_slot()
+    }
+
+    /// Whether the slot's value was applied here, its acceptor state is
+    /// gone and it must not be voted on again.
+    pub fn is_applied(&self, slot: i64) -> bool {
+        slot <= self.learner.get_last_applied_slot()
+    }
+
     pub fn prepare(&mut self, proposal: Proposal) -> Option<Promise> {
-        match self.acceptor.prepare(proposal) {
-            Some(prom) => Some(prom),
-            None => None,
+        if self.is_applied(proposal.get_slot()) {
+            return None;
         }
+        self.acceptor.prepare(proposal)
     }
 
-    pub fn accept(&mut self, proposal: Proposal) -> Option<Accept_Message> {
-        match self.acceptor.accept(proposal) {
-            Some(message) => Some(message),
-            None => None,
+    pub fn accept(&mut self, proposal: Proposal) -> Option<AcceptMessage> {
+        if self.is_applied(proposal.get_slot()) {
+            return None;
         }
+        self.acceptor.accept(proposal)
+    }
+
+    pub fn query(&mut self, query: &[u8]) -> Vec<u8> {
+        self.learner.query(query)
+    }
+
+    pub fn get_session(&self, client_id: &str) -> Option<Session> {
+        self.learner.get_session(client_id)
+    }
+
+    pub fn snapshot(&mut self) -> io::Result<Vec<u8>> {
+        self.learner.snapshot()
+    }
+
+    pub fn get_sessions(&self) -> &HashMap<String, Session> {
+        self.learner.get_sessions()
+    }
+
+    pub fn get_range_hashes(&self) -> Vec<RangeHash> {
+        self.learner.get_range_hashes()
+    }
+
+    /// Whether the applied state failed checksum verification.
+    pub fn is_corrupted(&self) -> bool {
+        self.learner.is_corrupted()
+    }
+
+    /// Whether this node has to be restored from a peer: its state is
+    /// corrupted or it missed a chosen slot.
+    pub fn needs_resync(&self) -> bool {
+        self.is_corrupted() || self.learner.is_stalled()
+    }
+
+    /// Seeds this node from a backup taken at `applied_slot`; the next
+    /// proposal goes to the slot after it.
+    pub fn restore(
+        &mut self,
+        applied_slot: i64,
+        state: &[u8],
+        sessions: HashMap<String, Session>,
+    ) -> io::Result<()> {
+        self.learner.restore(applied_slot, state, sessions)?;
+        self.last_committed_slot = self.last_committed_slot.max(applied_slot);
+        self.acceptor
+            .forget_through(self.learner.get_last_applied_slot());
+        Ok(())
     }
 
-    pub fn commit(&mut self, proposal: Proposal) {
-        self.learner.insert(proposal);
+    /// Learns a chosen proposal and returns the state machine's result,
+    /// `None` when it was not applied now, see `Learner::insert`.
+    pub fn commit(&mut self, proposal: Proposal) -> Option<Vec<u8>> {
+        self.last_committed_slot = self.last_committed_slot.max(proposal.get_slot());
+        let result = self.learner.insert(proposal);
+        self.acceptor
+            .forget_through(self.learner.get_last_applied_slot());
+        result
     }
 }
@@ -0,0 +1,74 @@
+/// Majority arithmetic over the full voter set of the cluster.
+///
+/// The voter count always includes the local node, which votes through its
+/// own `Proposer`/`Acceptor` like any remote peer does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quorum {
+    voters: usize,
+}
+
+impl Quorum {
+    pub fn new(voters: usize) -> Self {
+        Self { voters }
+    }
+
+    /// Builds the quorum for `peers` remote voters plus the local node.
+    pub fn with_local(peers: usize) -> Self {
+        Self::new(peers + 1)
+    }
+
+    pub fn get_voters(&self) -> usize {
+        self.voters
+    }
+
+    /// Smallest number of votes forming a strict majority: `(n / 2) + 1`.
+    pub fn get_size(&self) -> usize {
+        self.voters / 2 + 1
+    }
+
+    pub fn is_reached(&self, votes: usize) -> bool {
+        votes >= self.get_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quorum;
+
+    #[test]
+    fn majority_sizes_for_one_to_seven_voters() {
+        let expected = [(1, 1), (2, 2), (3, 2), (4, 3), (5, 3), (6, 4), (7, 4)];
+        for (voters, size) in expected {
+            assert_eq!(Quorum::new(voters).get_size(), size, "voters = {voters}");
+        }
+    }
+
+    #[test]
+    fn minority_is_never_a_quorum() {
+        for voters in 1..=7 {
+            let quorum = Quorum::new(voters);
+            for votes in 0..=voters {
+                assert_eq!(quorum.is_reached(votes), votes * 2 > voters);
+            }
+        }
+    }
+
+    #[test]
+    fn even_clusters_need_more_than_half() {
+        assert!(!Quorum::new(2).is_reached(1));
+        assert!(!Quorum::new(4).is_reached(2));
+        assert!(!Quorum::new(6).is_reached(3));
+    }
+
+    #[test]
+    fn local_node_is_counted_as_voter() {
+        let single = Quorum::with_local(0);
+        assert_eq!(single.get_voters(), 1);
+        assert!(single.is_reached(1));
+
+        let three = Quorum::with_local(2);
+        assert_eq!(three.get_voters(), 3);
+        assert!(!three.is_reached(1));
+        assert!(three.is_reached(2));
+    }
+}
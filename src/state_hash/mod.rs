@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+
+use prost::Message;
+
+use crate::checksum;
+use crate::proposal::Proposal;
+
+/// Slots covered by one range hash.
+pub const RANGE_SLOTS: i64 = 100;
+
+/// Ranges kept in memory, older ones are dropped.
+const MAX_RANGES: usize = 128;
+
+/// Hash of the entries applied in slots `first_slot..=last_slot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeHash {
+    pub first_slot: i64,
+    pub last_slot: i64,
+    pub hash: u64,
+    pub entries: u32,
+}
+
+/// Rolling hash of applied log entries, one per `RANGE_SLOTS` slots, so
+/// replicas can be compared without shipping their state.
+///
+/// Entry hashes are added up, so the order commits arrive in within a
+/// range does not matter, only which entries were applied. A slot committed
+/// again with the same value is hashed once; with a different value it is
+/// mixed into its range as well, so the range no longer matches the peers.
+/// Ranges that started before this node began hashing, at startup or after
+/// a restore, are never reported.
+#[derive(Debug, Default)]
+pub struct StateHasher {
+    ranges: BTreeMap<i64, RangeHash>,
+    // entry hash of every slot of the kept ranges
+    entry_hashes: BTreeMap<i64, u32>,
+    hashed_from: Option<i64>,
+}
+
+impl StateHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an applied entry to the hash of its range. Returns `false` when
+    /// the slot was hashed before with a different value, the log diverged.
+    pub fn record(&mut self, proposal: &Proposal) -> bool {
+        let slot = proposal.get_slot();
+        let hashed_from = *self.hashed_from.get_or_insert(slot - 1);
+        if slot <= hashed_from {
+            return true;
+        }
+        let entry_hash = Self::entry_hash(proposal);
+        let agrees = match self.entry_hashes.get(&slot) {
+            Some(hashed) if *hashed == entry_hash => return true,
+            Some(_) => false,
+            None => {
+                self.entry_hashes.insert(slot, entry_hash);
+                true
+            }
+        };
+
+        let first_slot = (slot - 1) / RANGE_SLOTS * RANGE_SLOTS + 1;
+        let range = self.ranges.entry(first_slot).or_insert(RangeHash {
+            first_slot,
+            last_slot: first_slot + RANGE_SLOTS - 1,
+            hash: 0,
+            entries: 0,
+        });
+        range.hash = range.hash.wrapping_add(entry_hash as u64);
+        if agrees {
+            range.entries += 1;
+        }
+
+        while self.ranges.len() > MAX_RANGES {
+            if let Some((_, dropped)) = self.ranges.pop_first() {
+                self.entry_hashes = self.entry_hashes.split_off(&(dropped.last_slot + 1));
+            }
+        }
+        agrees
+    }
+
+    /// Forgets every range, hashing restarts after `applied_slot`.
+    pub fn reset(&mut self, applied_slot: i64) {
+        self.ranges.clear();
+        self.entry_hashes.clear();
+        self.hashed_from = Some(applied_slot);
+    }
+
+    /// Ranges fully covered by this node's hashing and ending at or before
+    /// `applied_slot`, oldest first.
+    pub fn get_ranges(&self, applied_slot: i64) -> Vec<RangeHash> {
+        let hashed_from = match self.hashed_from {
+            Some(hashed_from) => hashed_from,
+            None => return vec![],
+        };
+        self.ranges
+            .values()
+            .filter(|range| range.first_slot > hashed_from && range.last_slot <= applied_slot)
+            .copied()
+            .collect()
+    }
+
+    fn entry_hash(proposal: &Proposal) -> u32 {
+        let mut data = proposal.get_slot().to_le_bytes().to_vec();
+        data.extend_from_slice(proposal.get_command());
+        if let Some(config) = proposal.get_config() {
+            data.extend(config.to_proto().encode_to_vec());
+        }
+        checksum::of(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(slot: i64, command: &str) -> Proposal {
+        Proposal::new(1, command.as_bytes().to_vec()).with_slot(slot)
+    }
+
+    fn hasher(slots: impl Iterator<Item = i64>) -> StateHasher {
+        let mut hasher = StateHasher::new();
+        for slot in slots {
+            hasher.record(&entry(slot, "set"));
+        }
+        hasher
+    }
+
+    #[test]
+    fn only_complete_ranges_are_reported() {
+        let hasher = hasher(1..=250);
+        let ranges = hasher.get_ranges(250);
+        assert_eq!(
+            ranges
+                .iter()
+                .map(|range| (range.first_slot, range.last_slot, range.entries))
+                .collect::<Vec<_>>(),
+            vec![(1, 100, 100), (101, 200, 100)]
+        );
+    }
+
+    #[test]
+    fn arrival_order_does_not_matter() {
+        let forward = hasher(1..=100);
+        let backward = hasher(std::iter::once(1).chain((2..=100).rev()));
+        assert_eq!(forward.get_ranges(100), backward.get_ranges(100));
+    }
+
+    #[test]
+    fn missed_or_different_entry_changes_the_range() {
+        let all = hasher(1..=100);
+        let missed = hasher((1..=100).filter(|slot| *slot != 42));
+        assert_ne!(all.get_ranges(100), missed.get_ranges(100));
+
+        let mut different = hasher((1..=100).filter(|slot| *slot != 42));
+        different.record(&entry(42, "other"));
+        assert_ne!(
+            all.get_ranges(100)[0].hash,
+            different.get_ranges(100)[0].hash
+        );
+    }
+
+    #[test]
+    fn recommitted_slot_is_hashed_once() {
+        let once = hasher(1..=100);
+        let twice = hasher((1..=100).chain(40..=60));
+        assert_eq!(once.get_ranges(100), twice.get_ranges(100));
+        assert_eq!(twice.get_ranges(100)[0].entries, 100);
+    }
+
+    #[test]
+    fn recommitted_slot_with_another_value_diverges() {
+        let once = hasher(1..=100);
+        let mut twice = hasher(1..=100);
+        assert!(twice.record(&entry(42, "set")));
+        assert!(!twice.record(&entry(42, "other")));
+
+        let (once, twice) = (once.get_ranges(100)[0], twice.get_ranges(100)[0]);
+        assert_ne!(once.hash, twice.hash);
+        assert_eq!(twice.entries, 100);
+    }
+
+    #[test]
+    fn partially_hashed_ranges_are_skipped() {
+        let restarted = hasher(57..=250);
+        assert_eq!(restarted.get_ranges(250)[0].first_slot, 101);
+
+        let mut restored = hasher(1..=150);
+        restored.reset(150);
+        for slot in 151..=300 {
+            restored.record(&entry(slot, "set"));
+        }
+        assert_eq!(restored.get_ranges(300)[0].first_slot, 201);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::io;
+
+use prost::Message;
+
+use super::StateMachine;
+use crate::proto::{ClientSession, KvCommand, KvEntry, KvPair, KvQuery, KvResult, KvSnapshot};
+use crate::store::KvStore;
+
+/// Keys starting with this prefix hold the state machine's own bookkeeping
+/// and cannot be written by clients.
+pub const RESERVED_PREFIX: &str = "\u{0}";
+
+/// Revision of `key` is stored under this prefix followed by `key`.
+const REVISION_PREFIX: &str = "\u{0}rev/";
+
+/// Session of a client is stored under this prefix followed by its id, as
+/// a hex encoded `ClientSession`.
+const SESSION_PREFIX: &str = "\u{0}session/";
+
+/// Last slot applied to the state.
+const APPLIED_SLOT_KEY: &str = "\u{0}applied";
+
+/// The built-in key/value state machine on top of a `KvStore` engine.
+///
+/// Every key carries a revision, the slot of its last write, kept in the
+/// same store so snapshots and backups include it.
+pub struct KvStateMachine {
+    store: Box<dyn KvStore>,
+}
+
+impl KvStateMachine {
+    pub fn new(store: Box<dyn KvStore>) -> Self {
+        Self { store }
+    }
+
+    pub fn set_command(key: &str, value: &str) -> Vec<u8> {
+        KvCommand {
+            key: key.to_string(),
+            value: Some(value.to_string()),
+            batch: vec![],
+        }
+        .encode_to_vec()
+    }
+
+    #[cfg(test)]
+    pub fn delete_command(key: &str) -> Vec<u8> {
+        KvCommand {
+            key: key.to_string(),
+            value: None,
+            batch: vec![],
+        }
+        .encode_to_vec()
+    }
+
+    /// Sets every pair in one command, chosen and applied in one slot.
+    pub fn batch_command(pairs: Vec<(String, String)>) -> Vec<u8> {
+        let batch = pairs
+            .into_iter()
+            .map(|(key, value)| KvCommand {
+                key,
+                value: Some(value),
+                batch: vec![],
+            })
+            .collect();
+        KvCommand {
+            key: String::new(),
+            value: None,
+            batch,
+        }
+        .encode_to_vec()
+    }
+
+    pub fn get_query(key: &str) -> Vec<u8> {
+        KvQuery {
+            key: key.to_string(),
+            prefix: None,
+        }
+        .encode_to_vec()
+    }
+
+    pub fn scan_query(prefix: &str) -> Vec<u8> {
+        KvQuery {
+            key: String::new(),
+            prefix: Some(prefix.to_string()),
+        }
+        .encode_to_vec()
+    }
+
+    /// Decodes a `query` result; an undecodable result reads as empty.
+    pub fn decode_result(result: &[u8]) -> KvResult {
+        KvResult::decode(result).unwrap_or_default()
+    }
+
+    /// Value carried by a `query` result, `None` when the key is missing or
+    /// the result is not a key/value result.
+    #[cfg(test)]
+    pub fn decode_value(result: &[u8]) -> Option<String> {
+        Self::decode_result(result).value
+    }
+
+    pub fn is_reserved(key: &str) -> bool {
+        key.starts_with(RESERVED_PREFIX)
+    }
+
+    fn apply_one(&mut self, slot: i64, command: &KvCommand) -> io::Result<()> {
+        if Self::is_reserved(&command.key) {
+            eprintln!("skipping write to reserved key {:?}", command.key);
+            return Ok(());
+        }
+        let revision_key = format!("{}{}", REVISION_PREFIX, command.key);
+        match &command.value {
+            Some(value) => {
+                self.store.set(&command.key, value)?;
+                self.store.set(&revision_key, &slot.to_string())
+            }
+            None => {
+                self.store.delete(&command.key)?;
+                self.store.delete(&revision_key)
+            }
+        }
+    }
+
+    fn revision(&mut self, key: &str) -> i64 {
+        self.store
+            .get(&format!("{}{}", REVISION_PREFIX, key))
+            .and_then(|revision| revision.parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Vec<KvEntry> {
+        let revisions: HashMap<String, i64> = self
+            .store
+            .scan(&format!("{}{}", REVISION_PREFIX, prefix))
+            .into_iter()
+            .map(|(key, revision)| {
+                (
+                    key[REVISION_PREFIX.len()..].to_string(),
+                    revision.parse().unwrap_or(0),
+                )
+            })
+            .collect();
+        self.store
+            .scan(prefix)
+            .into_iter()
+            .filter(|(key, _)| !Self::is_reserved(key))
+            .map(|(key, value)| KvEntry {
+                revision: revisions.get(&key).copied().unwrap_or(0),
+                key,
+                value,
+            })
+            .collect()
+    }
+}
+
+impl StateMachine for KvStateMachine {
+    fn apply(&mut self, slot: i64, command: &[u8]) -> io::Result<Vec<u8>> {
+        let command = match KvCommand::decode(command) {
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("skipping malformed key/value command {}", e);
+                return Ok(vec![]);
+            }
+        };
+        let commands = if command.batch.is_empty() {
+            vec![command]
+        } else {
+            command.batch
+        };
+        for command in commands.iter() {
+            self.apply_one(slot, command).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("cannot apply command on key {} {}", command.key, e),
+                )
+            })?;
+        }
+        Ok(vec![])
+    }
+
+    fn query(&mut self, query: &[u8]) -> Vec<u8> {
+        let query = match KvQuery::decode(query) {
+            Ok(query) => query,
+            Err(_) => return KvResult::default().encode_to_vec(),
+        };
+        let result = match &query.prefix {
+            Some(prefix) => KvResult {
+                entries: self.scan(prefix),
+                ..Default::default()
+            },
+            None if Self::is_reserved(&query.key) => KvResult::default(),
+            None => KvResult {
+                value: self.store.get(&query.key),
+                revision: self.revision(&query.key),
+                entries: vec![],
+            },
+        };
+        result.encode_to_vec()
+    }
+
+    fn snapshot(&mut self) -> io::Result<Vec<u8>> {
+        let pairs = self
+            .store
+            .snapshot()
+            .into_iter()
+            .map(|(key, value)| KvPair { key, value })
+            .collect();
+        Ok(KvSnapshot { pairs }.encode_to_vec())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> io::Result<()> {
+        let snapshot = KvSnapshot::decode(snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.store.restore(
+            snapshot
+                .pairs
+                .into_iter()
+                .map(|pair| (pair.key, pair.value))
+                .collect(),
+        )
+    }
+
+    fn is_corrupted(&self) -> bool {
+        self.store.is_corrupted()
+    }
+
+    fn save_session(&mut self, session: &ClientSession) -> io::Result<()> {
+        let encoded: String = session
+            .encode_to_vec()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.store.set(
+            &format!("{}{}", SESSION_PREFIX, session.client_id),
+            &encoded,
+        )
+    }
+
+    fn load_sessions(&mut self) -> io::Result<Vec<ClientSession>> {
+        self.store
+            .scan(SESSION_PREFIX)
+            .into_iter()
+            .map(|(key, encoded)| {
+                let bytes = (0..encoded.len())
+                    .step_by(2)
+                    .map(|i| {
+                        encoded
+                            .get(i..i + 2)
+                            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    })
+                    .collect::<Option<Vec<u8>>>();
+                bytes
+                    .and_then(|bytes| ClientSession::decode(bytes.as_slice()).ok())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("malformed session {:?}", key),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    fn save_applied_slot(&mut self, slot: i64) -> io::Result<()> {
+        self.store.set(APPLIED_SLOT_KEY, &slot.to_string())
+    }
+
+    fn load_applied_slot(&mut self) -> io::Result<i64> {
+        match self.store.get(APPLIED_SLOT_KEY) {
+            Some(slot) => slot.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed applied slot {:?} {}", slot, e),
+                )
+            }),
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn machine() -> KvStateMachine {
+        KvStateMachine::new(Box::new(MemoryStore::new()))
+    }
+
+    fn get(machine: &mut KvStateMachine, key: &str) -> KvResult {
+        KvStateMachine::decode_result(&machine.query(&KvStateMachine::get_query(key)))
+    }
+
+    #[test]
+    fn set_and_delete_commands() {
+        let mut machine = machine();
+        machine
+            .apply(1, &KvStateMachine::set_command("k", "v"))
+            .unwrap();
+        assert_eq!(get(&mut machine, "k").value, Some(String::from("v")));
+
+        machine
+            .apply(2, &KvStateMachine::delete_command("k"))
+            .unwrap();
+        assert_eq!(get(&mut machine, "k").value, None);
+        assert_eq!(get(&mut machine, "k").revision, 0);
+    }
+
+    #[test]
+    fn revision_is_the_slot_of_the_last_write() {
+        let mut machine = machine();
+        machine
+            .apply(3, &KvStateMachine::set_command("k", "v1"))
+            .unwrap();
+        machine
+            .apply(7, &KvStateMachine::set_command("k", "v2"))
+            .unwrap();
+        assert_eq!(get(&mut machine, "k").revision, 7);
+    }
+
+    #[test]
+    fn batch_is_applied_in_one_slot_and_scanned_by_prefix() {
+        let mut machine = machine();
+        machine
+            .apply(
+                4,
+                &KvStateMachine::batch_command(vec![
+                    (String::from("user/1"), String::from("a")),
+                    (String::from("user/2"), String::from("b")),
+                    (String::from("order/1"), String::from("x")),
+                ]),
+            )
+            .unwrap();
+
+        let result =
+            KvStateMachine::decode_result(&machine.query(&KvStateMachine::scan_query("user/")));
+        let entries: Vec<(&str, &str, i64)> = result
+            .entries
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.as_str(), entry.revision))
+            .collect();
+        assert_eq!(entries, vec![("user/1", "a", 4), ("user/2", "b", 4)]);
+    }
+
+    #[test]
+    fn reserved_keys_are_neither_written_nor_scanned() {
+        let mut machine = machine();
+        machine
+            .apply(1, &KvStateMachine::set_command("k", "v"))
+            .unwrap();
+        machine
+            .apply(2, &KvStateMachine::set_command("\u{0}rev/k", "99"))
+            .unwrap();
+
+        assert_eq!(get(&mut machine, "k").revision, 1);
+        let result = KvStateMachine::decode_result(&machine.query(&KvStateMachine::scan_query("")));
+        assert_eq!(result.entries.len(), 1);
+    }
+
+    #[test]
+    fn snapshot_restores_into_another_replica() {
+        let mut source = machine();
+        source
+            .apply(1, &KvStateMachine::set_command("a", "1"))
+            .unwrap();
+        source
+            .apply(2, &KvStateMachine::set_command("b", "2"))
+            .unwrap();
+
+        let mut target = machine();
+        target
+            .apply(1, &KvStateMachine::set_command("stale", "x"))
+            .unwrap();
+        target.restore(&source.snapshot().unwrap()).unwrap();
+
+        assert_eq!(get(&mut target, "a").value, Some(String::from("1")));
+        assert_eq!(get(&mut target, "b").revision, 2);
+        assert_eq!(get(&mut target, "stale").value, None);
+    }
+
+    #[test]
+    fn sessions_are_kept_with_the_state() {
+        let mut machine = machine();
+        let session = ClientSession {
+            client_id: String::from("client"),
+            sequence: 3,
+            result: vec![0, 1, 0xff],
+        };
+        machine.save_session(&session).unwrap();
+
+        let mut restored = self::machine();
+        restored.restore(&machine.snapshot().unwrap()).unwrap();
+        assert_eq!(restored.load_sessions().unwrap(), vec![session]);
+        let result =
+            KvStateMachine::decode_result(&restored.query(&KvStateMachine::scan_query("")));
+        assert!(result.entries.is_empty());
+    }
+
+    #[test]
+    fn malformed_command_changes_nothing() {
+        let mut machine = machine();
+        machine.apply(1, &[0xff, 0xff]).unwrap();
+        assert_eq!(get(&mut machine, "k").value, None);
+    }
+}
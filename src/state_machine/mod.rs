@@ -0,0 +1,55 @@
+use std::io;
+
+use crate::proto::ClientSession;
+
+mod kv;
+
+pub use kv::KvStateMachine;
+
+/// Application state replicated by the log. Consensus only orders opaque
+/// commands; what they mean is up to the implementation.
+///
+/// `apply` must be deterministic: every replica applies the same commands
+/// in the same order and has to end up in the same state.
+pub trait StateMachine: Send {
+    /// Applies the command chosen for `slot` and returns the result sent
+    /// to the client. On error the slot counts as not applied, the node
+    /// stops applying and re-syncs from a peer.
+    fn apply(&mut self, slot: i64, command: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Answers a read-only query from the local state.
+    fn query(&mut self, query: &[u8]) -> Vec<u8>;
+
+    fn snapshot(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Replaces the whole state with a snapshot taken by `snapshot`.
+    fn restore(&mut self, snapshot: &[u8]) -> io::Result<()>;
+
+    /// Whether the local state failed verification and has to be restored
+    /// from a healthy replica.
+    fn is_corrupted(&self) -> bool {
+        false
+    }
+
+    /// Keeps the latest command applied for a client with the state, so
+    /// retries are still recognised after a restart. Not kept by default.
+    fn save_session(&mut self, _session: &ClientSession) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sessions kept by `save_session`, read once when the node starts.
+    fn load_sessions(&mut self) -> io::Result<Vec<ClientSession>> {
+        Ok(vec![])
+    }
+
+    /// Keeps the last slot applied with the state, so a restarted node
+    /// neither applies a slot twice nor skips one. Not kept by default.
+    fn save_applied_slot(&mut self, _slot: i64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Slot kept by `save_applied_slot`, 0 when nothing was applied.
+    fn load_applied_slot(&mut self) -> io::Result<i64> {
+        Ok(0)
+    }
+}
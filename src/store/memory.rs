@@ -0,0 +1,93 @@
+use std::io;
+
+use super::{KvStore, Snapshot};
+
+/// Store kept entirely in memory, for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    data: Snapshot,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.data.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        self.data.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.data.remove(key);
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: &str) -> Vec<(String, String)> {
+        self.data
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn snapshot(&mut self) -> Snapshot {
+        self.data.clone()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> io::Result<()> {
+        self.data = snapshot;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_returns_only_matching_prefix_in_order() {
+        let mut store = MemoryStore::new();
+        store.set("user/2", "b").unwrap();
+        store.set("order/1", "x").unwrap();
+        store.set("user/1", "a").unwrap();
+
+        let users = store.scan("user/");
+        assert_eq!(
+            users,
+            vec![
+                (String::from("user/1"), String::from("a")),
+                (String::from("user/2"), String::from("b")),
+            ]
+        );
+        assert_eq!(store.scan("").len(), 3);
+    }
+
+    #[test]
+    fn delete_removes_the_key() {
+        let mut store = MemoryStore::new();
+        store.set("k", "v").unwrap();
+        store.delete("k").unwrap();
+        assert_eq!(store.get("k"), None);
+    }
+
+    #[test]
+    fn restore_replaces_the_contents() {
+        let mut store = MemoryStore::new();
+        store.set("a", "1").unwrap();
+        let snapshot = store.snapshot();
+
+        store.set("a", "2").unwrap();
+        store.set("b", "3").unwrap();
+        store.restore(snapshot).unwrap();
+
+        assert_eq!(store.get("a"), Some(String::from("1")));
+        assert_eq!(store.get("b"), None);
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::BTreeMap;
+use std::io;
+
+#[cfg(test)]
+mod memory;
+mod veresiye_store;
+
+#[cfg(test)]
+pub use memory::MemoryStore;
+pub use veresiye_store::VeresiyeStore;
+
+/// Full copy of a store's contents, ordered by key.
+pub type Snapshot = BTreeMap<String, String>;
+
+/// Key/value engine the learner applies chosen values to.
+pub trait KvStore: Send {
+    fn get(&mut self, key: &str) -> Option<String>;
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()>;
+
+    fn delete(&mut self, key: &str) -> io::Result<()>;
+
+    /// Every pair whose key starts with `prefix`, ordered by key.
+    fn scan(&mut self, prefix: &str) -> Vec<(String, String)>;
+
+    fn snapshot(&mut self) -> Snapshot {
+        self.scan("").into_iter().collect()
+    }
+
+    /// Replaces the whole contents with `snapshot`.
+    fn restore(&mut self, snapshot: Snapshot) -> io::Result<()> {
+        for (key, _) in self.scan("") {
+            if !snapshot.contains_key(&key) {
+                self.delete(&key)?;
+            }
+        }
+        for (key, value) in snapshot.iter() {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a record failed checksum verification since the last
+    /// `restore`; a corrupted store must not be served from.
+    fn is_corrupted(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use veresiye::Veresiye;
+
+use super::{KvStore, Snapshot};
+use crate::checksum;
+
+/// Default engine, an LSM tree on local disk.
+///
+/// Veresiye cannot iterate its keys, so the key set is tracked in a
+/// `keys` file next to the data: one JSON encoded key per line, prefixed
+/// with `+` when set and `-` when deleted, replayed on open and compacted
+/// to the live keys. A store holding data without that file was written
+/// before the index existed and is refused, its keys cannot be listed.
+///
+/// A key is added to the index, synced, before its value is written and
+/// removed after its value is deleted, so a crash in between leaves at
+/// most an indexed key without a value, which reads as missing.
+///
+/// Values and index lines are sealed with a CRC32C checksum. A record that
+/// fails verification is never served; the store is marked corrupted
+/// instead, until a `restore` replaces its contents. Stores written before
+/// checksums existed have no `format` file and are sealed once on open.
+pub struct VeresiyeStore {
+    database: Veresiye,
+    keys: BTreeSet<String>,
+    index: File,
+    corrupted: bool,
+}
+
+/// Record format written by this version, kept in the `format` file.
+const FORMAT_VERSION: u32 = 2;
+
+/// Format of stores without a `format` file: values and index lines are
+/// stored without checksums.
+const LEGACY_FORMAT_VERSION: u32 = 1;
+
+impl VeresiyeStore {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let index_path = Path::new(path).join("keys");
+        let format_path = Path::new(path).join("format");
+        if !index_path.exists() && Self::has_data(Path::new(path))? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} holds data written without a key index, start from an empty directory and restore a backup with RESTORE_FROM",
+                    path
+                ),
+            ));
+        }
+        let mut database = Veresiye::new(path.to_string())?;
+
+        let format = match fs::read_to_string(&format_path) {
+            Ok(format) => format.trim().parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unreadable store format {:?} {}", format, e),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && index_path.exists() => {
+                LEGACY_FORMAT_VERSION
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => FORMAT_VERSION,
+            Err(e) => return Err(e),
+        };
+        if format > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("store format {} is newer than {}", format, FORMAT_VERSION),
+            ));
+        }
+        let sealed = format != LEGACY_FORMAT_VERSION;
+
+        let mut keys = BTreeSet::new();
+        let mut corrupted = false;
+        let mut lines = 0;
+        if index_path.exists() {
+            for line in BufReader::new(File::open(&index_path)?).lines() {
+                let line = line?;
+                lines += 1;
+                match Self::parse_index_entry(&line, sealed) {
+                    Ok(('+', key)) => keys.insert(key),
+                    Ok((_, key)) => keys.remove(&key),
+                    Err(e) => {
+                        eprintln!("corrupt key index entry {:?} {}", line, e);
+                        corrupted = true;
+                        continue;
+                    }
+                };
+            }
+        }
+        if !sealed {
+            Self::seal_legacy(&mut database, &keys, &index_path)?;
+        } else if lines > keys.len() && !corrupted {
+            // deletes and re-adds only grow the file, keep the live keys;
+            // a corrupt index is left as it is for inspection
+            Self::write_index(&keys, &index_path)?;
+            println!(
+                "compacted key index from {} to {} entries",
+                lines,
+                keys.len()
+            );
+        }
+        fs::write(&format_path, FORMAT_VERSION.to_string())?;
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)?;
+
+        Ok(Self {
+            database,
+            keys,
+            index,
+            corrupted,
+        })
+    }
+
+    /// Whether Veresiye files at `path` hold any data: a table, or a
+    /// non-empty write-ahead log segment.
+    fn has_data(path: &Path) -> io::Result<bool> {
+        let non_empty = |dir: &str, min_len: u64| -> io::Result<bool> {
+            let dir = path.join(dir);
+            if !dir.exists() {
+                return Ok(false);
+            }
+            for entry in fs::read_dir(dir)? {
+                if entry?.metadata()?.len() >= min_len {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        Ok(non_empty("tables", 0)? || non_empty("log", 1)?)
+    }
+
+    /// Seals the values and index lines of a store written without
+    /// checksums. Values that already verify were sealed by an interrupted
+    /// earlier run.
+    fn seal_legacy(
+        database: &mut Veresiye,
+        keys: &BTreeSet<String>,
+        index_path: &Path,
+    ) -> io::Result<()> {
+        for key in keys {
+            if let Some(value) = database.get(key) {
+                if checksum::unseal(&value).is_err() {
+                    database.set(key, &checksum::seal(&value));
+                }
+            }
+        }
+        Self::write_index(keys, index_path)?;
+        println!("sealed {} keys written without checksums", keys.len());
+        Ok(())
+    }
+
+    /// Replaces the index with one `+` line per key.
+    fn write_index(keys: &BTreeSet<String>, index_path: &Path) -> io::Result<()> {
+        let temp_path = index_path.with_extension("rewrite");
+        let mut temp = File::create(&temp_path)?;
+        for key in keys {
+            let key = serde_json::to_string(key)?;
+            writeln!(temp, "{}", checksum::seal(&format!("+{}", key)))?;
+        }
+        temp.sync_all()?;
+        fs::rename(&temp_path, index_path)
+    }
+
+    fn parse_index_entry(line: &str, sealed: bool) -> io::Result<(char, String)> {
+        let entry = if sealed {
+            checksum::unseal(line)?
+        } else {
+            line
+        };
+        let (op, key) = entry.split_at(entry.len().min(1));
+        let key: String =
+            serde_json::from_str(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match op {
+            "+" => Ok(('+', key)),
+            "-" => Ok(('-', key)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown operation {:?}", op),
+            )),
+        }
+    }
+
+    fn record(&mut self, op: char, key: &str) -> io::Result<()> {
+        let key = serde_json::to_string(key)?;
+        writeln!(self.index, "{}", checksum::seal(&format!("{}{}", op, key)))?;
+        self.index.sync_data()
+    }
+
+    fn read(&mut self, key: &str) -> Option<String> {
+        let sealed = self.database.get(key)?;
+        match checksum::unseal(&sealed) {
+            Ok(value) => Some(value.to_string()),
+            Err(e) => {
+                eprintln!("corrupt value for key {:?} {}", key, e);
+                self.corrupted = true;
+                None
+            }
+        }
+    }
+}
+
+impl KvStore for VeresiyeStore {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.read(key)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        if !self.keys.contains(key) {
+            self.record('+', key)?;
+            self.keys.insert(key.to_string());
+        }
+        self.database.set(key, &checksum::seal(value));
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> io::Result<()> {
+        self.database.delete(key);
+        if self.keys.remove(key) {
+            self.record('-', key)?;
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, prefix: &str) -> Vec<(String, String)> {
+        let keys: Vec<String> = self
+            .keys
+            .range(prefix.to_string()..)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.read(&key).map(|value| (key, value)))
+            .collect()
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> io::Result<()> {
+        for key in self.keys.clone() {
+            if !snapshot.contains_key(&key) {
+                self.delete(&key)?;
+            }
+        }
+        for (key, value) in snapshot.iter() {
+            self.set(key, value)?;
+        }
+        self.corrupted = false;
+        Ok(())
+    }
+
+    fn is_corrupted(&self) -> bool {
+        self.corrupted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("veresiye-paxos-{}-{}", name, std::process::id()));
+        // Veresiye only initialises directories it creates itself
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn values_survive_reopening() {
+        let dir = store_dir("reopen");
+        let path = dir.to_str().unwrap();
+        {
+            let mut store = VeresiyeStore::open(path).unwrap();
+            store.set("a", "1").unwrap();
+            store.set("b", "2").unwrap();
+            store.delete("b").unwrap();
+        }
+        let mut store = VeresiyeStore::open(path).unwrap();
+        assert_eq!(store.get("a"), Some(String::from("1")));
+        assert_eq!(store.scan(""), vec![(String::from("a"), String::from("1"))]);
+        assert!(!store.is_corrupted());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn indexed_key_without_a_value_reads_as_missing() {
+        let dir = store_dir("interrupted");
+        let path = dir.to_str().unwrap();
+        {
+            let mut store = VeresiyeStore::open(path).unwrap();
+            store.set("a", "1").unwrap();
+            // a crash after indexing "b" and before writing its value
+            store.record('+', "b").unwrap();
+        }
+        let mut store = VeresiyeStore::open(path).unwrap();
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.scan(""), vec![(String::from("a"), String::from("1"))]);
+        assert!(!store.is_corrupted());
+
+        store.set("b", "2").unwrap();
+        assert_eq!(store.get("b"), Some(String::from("2")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn index_is_compacted_on_open() {
+        let dir = store_dir("compact");
+        let path = dir.to_str().unwrap();
+        {
+            let mut store = VeresiyeStore::open(path).unwrap();
+            for round in 0..3 {
+                store.set("a", &round.to_string()).unwrap();
+                store.set("b", "1").unwrap();
+                store.delete("b").unwrap();
+            }
+        }
+        let index = || fs::read_to_string(dir.join("keys")).unwrap();
+        assert_eq!(index().lines().count(), 7);
+
+        let mut store = VeresiyeStore::open(path).unwrap();
+        assert_eq!(index().lines().count(), 1);
+        assert_eq!(store.scan(""), vec![(String::from("a"), String::from("2"))]);
+        assert!(!store.is_corrupted());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn data_without_an_index_is_refused() {
+        let dir = store_dir("unindexed");
+        let path = dir.to_str().unwrap();
+        Veresiye::new(path.to_string()).unwrap();
+        // an empty store has nothing to lose
+        drop(VeresiyeStore::open(path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+
+        {
+            // written before the key index existed
+            let mut database = Veresiye::new(path.to_string()).unwrap();
+            database.set("a", "1");
+        }
+        let opened = VeresiyeStore::open(path);
+        assert_eq!(opened.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(!dir.join("keys").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_store_is_sealed_on_open() {
+        let dir = store_dir("legacy");
+        let path = dir.to_str().unwrap();
+        {
+            // written by a version without checksums or a format file
+            let mut database = Veresiye::new(path.to_string()).unwrap();
+            database.set("a", "1");
+            database.set("b", "2");
+            fs::write(dir.join("keys"), "+\"a\"\n+\"b\"\n-\"b\"\n").unwrap();
+        }
+        {
+            let mut store = VeresiyeStore::open(path).unwrap();
+            assert!(!store.is_corrupted());
+            assert_eq!(store.get("a"), Some(String::from("1")));
+            assert_eq!(store.get("b"), None);
+        }
+        let mut store = VeresiyeStore::open(path).unwrap();
+        assert!(!store.is_corrupted());
+        assert_eq!(store.scan(""), vec![(String::from("a"), String::from("1"))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
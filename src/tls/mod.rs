@@ -0,0 +1,320 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+use tonic::Request;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::connection::ConnectionManager;
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// DNS subject alternative name identifying a node's certificate. Peers
+/// dialing node `node_id` only accept a server certificate carrying it.
+pub fn node_name(node_id: i32) -> String {
+    format!("node-{}", node_id)
+}
+
+/// Server and peer TLS material loaded from PEM files.
+///
+/// The server certificate, key and CA are re-read whenever one of the
+/// files changes; new connections use the new files while established
+/// ones keep the certificates they were opened with.
+pub struct TlsManager {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    ca_path: PathBuf,
+    require_client_cert: bool,
+    state: RwLock<TlsState>,
+}
+
+struct TlsState {
+    acceptor: TlsAcceptor,
+    identity: Identity,
+    ca: Certificate,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl TlsManager {
+    /// Loads the files. Clients without a certificate are rejected during
+    /// the handshake unless `require_client_cert` is false; peer requests
+    /// need a node certificate either way.
+    pub fn load(
+        cert_path: &str,
+        key_path: &str,
+        ca_path: &str,
+        require_client_cert: bool,
+    ) -> io::Result<Self> {
+        let cert_path = PathBuf::from(cert_path);
+        let key_path = PathBuf::from(key_path);
+        let ca_path = PathBuf::from(ca_path);
+        let state = TlsState::load(&cert_path, &key_path, &ca_path, require_client_cert)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            ca_path,
+            require_client_cert,
+            state: RwLock::new(state),
+        })
+    }
+
+    pub fn get_acceptor(&self) -> TlsAcceptor {
+        self.state.read().unwrap().acceptor.clone()
+    }
+
+    /// Client configuration for dialing `node_id`, presenting this node's
+    /// certificate.
+    pub fn get_client_config(&self, node_id: i32) -> ClientTlsConfig {
+        let state = self.state.read().unwrap();
+        ClientTlsConfig::new()
+            .ca_certificate(state.ca.clone())
+            .identity(state.identity.clone())
+            .domain_name(node_name(node_id))
+    }
+
+    /// Re-reads the files if any of them changed since the last load.
+    /// A broken update is reported and the current certificates are kept.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = modified_times(&[&self.cert_path, &self.key_path, &self.ca_path]);
+        if self.state.read().unwrap().modified == modified {
+            return Ok(false);
+        }
+        let state = TlsState::load(
+            &self.cert_path,
+            &self.key_path,
+            &self.ca_path,
+            self.require_client_cert,
+        );
+        let mut current = self.state.write().unwrap();
+        match state {
+            Ok(state) => {
+                *current = state;
+                Ok(true)
+            }
+            Err(e) => {
+                // retried once the files change again
+                current.modified = modified;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl TlsState {
+    fn load(
+        cert_path: &PathBuf,
+        key_path: &PathBuf,
+        ca_path: &PathBuf,
+        require_client_cert: bool,
+    ) -> io::Result<Self> {
+        let modified = modified_times(&[cert_path, key_path, ca_path]);
+        let cert_pem = fs::read(cert_path)?;
+        let key_pem = fs::read(key_path)?;
+        let ca_pem = fs::read(ca_path)?;
+
+        let certs =
+            rustls_pemfile::certs(&mut cert_pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut key_pem.as_slice())?
+            .ok_or_else(|| invalid(format!("no private key in {}", key_path.display())))?;
+        let mut roots = RootCertStore::empty();
+        for ca in rustls_pemfile::certs(&mut ca_pem.as_slice()) {
+            roots.add(ca?).map_err(invalid)?;
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+        let verifier = if require_client_cert {
+            verifier.build()
+        } else {
+            verifier.allow_unauthenticated().build()
+        }
+        .map_err(invalid)?;
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)
+            .map_err(invalid)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            identity: Identity::from_pem(&cert_pem, &key_pem),
+            ca: Certificate::from_pem(&ca_pem),
+            modified,
+        })
+    }
+}
+
+fn modified_times(paths: &[&PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+fn invalid(e: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Node id of the client certificate `request` was made with, taken from
+/// its `node-<id>` DNS name. `None` over plaintext or for non-node clients.
+pub fn peer_node_id<T>(request: &Request<T>) -> Option<i32> {
+    let certs = request.peer_certs()?;
+    node_id_from_cert(certs.first()?)
+}
+
+fn node_id_from_cert(cert: &CertificateDer) -> Option<i32> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let names = cert.subject_alternative_name().ok()??;
+    names
+        .value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::DNSName(name) => name.strip_prefix("node-")?.parse().ok(),
+            _ => None,
+        })
+}
+
+/// Accepts TCP connections on `listener` and yields them once the TLS
+/// handshake finished, each handshake using the certificates current at
+/// the time. Handshakes run in their own task so a slow client does not
+/// hold up the others.
+pub fn incoming(
+    listener: TcpListener,
+    tls: Arc<TlsManager>,
+) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+    let (sender, receiver) = mpsc::channel(64);
+    tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("cannot accept connection {}", e);
+                    continue;
+                }
+            };
+            let acceptor = tls.get_acceptor();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Err(e) => eprintln!("TLS handshake with {} failed {}", addr, e),
+                }
+            });
+        }
+    });
+    ReceiverStream::new(receiver)
+}
+
+/// Watches the certificate files and drops cached peer channels after a
+/// reload so new connections present the new certificate.
+pub fn spawn_reloader(tls: Arc<TlsManager>, connections: Arc<ConnectionManager>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match tls.reload_if_changed() {
+                Ok(true) => {
+                    connections.reset().await;
+                    println!("TLS certificates reloaded");
+                }
+                Ok(false) => {}
+                Err(e) => eprintln!("TLS certificates not reloaded {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::Path;
+
+    use rcgen::CertifiedKey;
+
+    use super::*;
+
+    fn cert(names: &[&str]) -> CertifiedKey {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        rcgen::generate_simple_self_signed(names).unwrap()
+    }
+
+    #[test]
+    fn node_id_comes_from_the_node_name() {
+        let node_id = |names: &[&str]| node_id_from_cert(cert(names).cert.der());
+        assert_eq!(node_id(&["node-7"]), Some(7));
+        assert_eq!(node_id(&["paxos.example", "node-3"]), Some(3));
+        assert_eq!(node_id(&["paxos.example"]), None);
+        assert_eq!(node_id(&["node-x", "nodes-4"]), None);
+        assert_eq!(node_id(&[]), None);
+    }
+
+    /// Writes a self-signed certificate, its key and itself as the CA.
+    fn write_files(dir: &Path, name: &str) {
+        let CertifiedKey { cert, key_pair } = cert(&[name]);
+        fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), key_pair.serialize_pem()).unwrap();
+        fs::write(dir.join("ca.pem"), cert.pem()).unwrap();
+    }
+
+    /// Moves the modification time forward, file systems with a coarse
+    /// clock could otherwise miss a change.
+    fn touch(path: &Path, seconds: u64) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    #[test]
+    fn broken_reload_keeps_the_current_certificates() {
+        let dir = std::env::temp_dir().join(format!("veresiye-paxos-tls-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        write_files(&dir, "node-1");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let tls =
+            TlsManager::load(&path("cert.pem"), &path("key.pem"), &path("ca.pem"), true).unwrap();
+        assert!(!tls.reload_if_changed().unwrap());
+
+        let loaded = tls.get_acceptor().config().clone();
+        fs::write(dir.join("cert.pem"), "not a certificate").unwrap();
+        touch(&dir.join("cert.pem"), 10);
+        assert!(tls.reload_if_changed().is_err());
+        assert!(Arc::ptr_eq(&loaded, tls.get_acceptor().config()));
+        // reported once, not on every check
+        assert!(!tls.reload_if_changed().unwrap());
+
+        write_files(&dir, "node-1");
+        for name in ["cert.pem", "key.pem", "ca.pem"] {
+            touch(&dir.join(name), 20);
+        }
+        assert!(tls.reload_if_changed().unwrap());
+        assert!(!Arc::ptr_eq(&loaded, tls.get_acceptor().config()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}